@@ -1,3 +1,4 @@
+-- Changes to existing tables need a migration in `database::migrate()` (see `SCHEMA_VERSION`)
 PRAGMA foreign_keys = on;
 
 CREATE TABLE IF NOT EXISTS Users (
@@ -10,6 +11,60 @@ CREATE TABLE IF NOT EXISTS Users (
     last_accessed DATE DEFAULT CURRENT_TIMESTAMP NOT NULL
 );
 
+CREATE TABLE IF NOT EXISTS UserCredentials (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_fk INTEGER NOT NULL,
+    host TEXT NOT NULL,
+    username TEXT,        -- nullable, the username from the url is used if unset
+    token TEXT,           -- nullable, HTTPS personal access token
+    ssh_key TEXT,         -- nullable, private ssh key
+    ssh_passphrase TEXT,  -- nullable
+    date_created DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_modified DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    UNIQUE (user_fk, host),
+    FOREIGN KEY (user_fk)
+        REFERENCES Users(id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS IdentityAliases (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_fk INTEGER NOT NULL,
+    canonical_name TEXT,  -- nullable, the alias's name is kept if unset
+    canonical_email TEXT, -- nullable, the alias's email is kept if unset
+    alias_name TEXT DEFAULT '' NOT NULL, -- matches any name if empty
+    alias_email TEXT NOT NULL,
+    date_created DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    UNIQUE (user_fk, alias_name, alias_email),
+    FOREIGN KEY (user_fk)
+        REFERENCES Users(id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS RepoGroups (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_fk INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    url TEXT NOT NULL,    -- one row per repo in the group
+    date_created DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    UNIQUE (user_fk, name, url),
+    FOREIGN KEY (user_fk)
+        REFERENCES Users(id)
+            ON UPDATE CASCADE
+            ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS RepoCache (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT UNIQUE NOT NULL, -- the directory of the clone in the tmp directory
+    url TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    last_accessed INTEGER NOT NULL, -- epoch timestamp
+    date_created DATE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
 CREATE TABLE IF NOT EXISTS Hosts (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     host_name TEXT NOT NULL
@@ -17,9 +72,10 @@ CREATE TABLE IF NOT EXISTS Hosts (
 
 CREATE TABLE IF NOT EXISTS Repos (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
-    owner_fk INTEGER NOT NULL,
-    host_fk INTEGER NOT NULL,
+    owner_fk INTEGER,     -- nullable, analyzed repos don't need an owner
+    host_fk INTEGER,      -- nullable, local repos don't have a host
     repo_name TEXT NOT NULL,
+    url TEXT UNIQUE,      -- the url the repo was analyzed from
     FOREIGN KEY (owner_fk)
         REFERENCES Users(id)
             ON UPDATE CASCADE
@@ -33,14 +89,24 @@ CREATE TABLE IF NOT EXISTS Repos (
 CREATE TABLE IF NOT EXISTS Commits (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     repo_fk INTEGER NOT NULL,
-    user_fk INTEGER NOT NULL,
-    committer_username TEXT NOT NULL,
-    committer_email TEXT NOT NULL,
-    timestamp DATE NOT NULL,
+    user_fk INTEGER,      -- nullable, authors don't need to be users
+    oid TEXT NOT NULL,
+    diff_options TEXT DEFAULT '' NOT NULL, -- identifies the options the stats were computed with
+    author_name TEXT NOT NULL,
+    author_email TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    files_changed INTEGER NOT NULL,
+    files_renamed INTEGER DEFAULT 0 NOT NULL,
+    insertions INTEGER NOT NULL,
+    deletions INTEGER NOT NULL,
+    excluded_files INTEGER DEFAULT 0 NOT NULL,      -- changes left out of the counts above
+    excluded_insertions INTEGER DEFAULT 0 NOT NULL,
+    excluded_deletions INTEGER DEFAULT 0 NOT NULL,
     date_created DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
     last_modified DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
     projected_start DATE, -- nullable on purpose
     set_start DATE,       -- nullable on purpose
+    UNIQUE (repo_fk, oid, diff_options),
     FOREIGN KEY (user_fk)
         REFERENCES Users(id)
             ON UPDATE CASCADE
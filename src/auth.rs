@@ -2,10 +2,10 @@
 // This includes structs etc
 
 use actix_session::Session;
-use actix_web::{http, web::{self, Redirect}, Responder};
+use actix_web::{http, web::{self, Data, Redirect}, Responder};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use git2::Config;
-use git_stats_web::{database::User, errors::AppError};
+use git_stats_web::{database::{IdentityAliases, RepoGroups, User, UserCredentials}, errors::AppError, secrets::SecretCipher};
 use log::{debug, warn};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
@@ -141,3 +141,134 @@ pub async fn logout(session: Session) -> impl Responder {
 
     return Redirect::to("/login");
 }
+
+#[derive(Deserialize, Debug)]
+pub struct CredentialsFormData {
+    pub host: String,
+    pub username: Option<String>,
+    pub token: Option<String>,
+    pub ssh_key: Option<String>,
+    pub ssh_passphrase: Option<String>,
+}
+
+/// Stores the credentials used for fetching private repos from a host for the logged in user.
+pub async fn credentials_handler(session: Session, db: DbPool, secrets: Data<SecretCipher>, info: web::Form<CredentialsFormData>) -> impl Responder {
+
+    let user = match User::from_session(&session, &**db).await {
+        Some(v) => v,
+        None => return ("Not Logged In!".to_string(), http::StatusCode::UNAUTHORIZED),
+    };
+
+    let Some(user_id) = user.id else {
+        warn!("Logged in user: `{}` doesn't have an id!", user.email);
+        return ("Failed to save credentials!".to_string(), http::StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    // Credentials are only stored encrypted
+    if !secrets.is_enabled() {
+        return ("This server can't store credentials!".to_string(), http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    // Empty form fields are treated as unset
+    let non_empty = |v: Option<String>| v.filter(|v| !v.trim().is_empty());
+
+    let form_data = info.into_inner();
+    let credentials = UserCredentials {
+        user_fk: user_id,
+        host: form_data.host.trim().to_lowercase(),
+        username: non_empty(form_data.username),
+        token: non_empty(form_data.token),
+        ssh_key: non_empty(form_data.ssh_key),
+        ssh_passphrase: non_empty(form_data.ssh_passphrase),
+    };
+
+    return match credentials.push_update(&secrets, &**db).await {
+        Ok(_) => (format!("Saved credentials for `{}`!", credentials.host), http::StatusCode::OK),
+        Err(e) => {
+            warn!("Failed to save credentials with error: `{:?}`", e);
+            ("Failed to save credentials!".to_string(), http::StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    };
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AliasFormData {
+    pub canonical_name: Option<String>,
+    pub canonical_email: Option<String>,
+    pub alias_name: Option<String>,
+    pub alias_email: String,
+}
+
+/// Stores an identity alias for the logged in user.
+/// Commits by the alias are reported as the canonical identity.
+pub async fn alias_handler(session: Session, db: DbPool, info: web::Form<AliasFormData>) -> impl Responder {
+
+    let user = match User::from_session(&session, &**db).await {
+        Some(v) => v,
+        None => return ("Not Logged In!".to_string(), http::StatusCode::UNAUTHORIZED),
+    };
+
+    // Empty form fields are treated as unset
+    let non_empty = |v: Option<String>| v.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+
+    let form_data = info.into_inner();
+    let alias = IdentityAliases {
+        user_fk: user.id.unwrap_or_default(),
+        canonical_name: non_empty(form_data.canonical_name),
+        canonical_email: non_empty(form_data.canonical_email),
+        alias_name: non_empty(form_data.alias_name),
+        alias_email: form_data.alias_email.trim().to_string(),
+    };
+
+    if alias.alias_email.is_empty() || (alias.canonical_name.is_none() && alias.canonical_email.is_none()) {
+        return ("An alias needs an `alias_email` and a canonical name or email!".to_string(), http::StatusCode::BAD_REQUEST);
+    }
+
+    return match alias.push_update(&**db).await {
+        Ok(_) => (format!("Saved alias for `{}`!", alias.alias_email), http::StatusCode::OK),
+        Err(e) => {
+            warn!("Failed to save alias with error: `{:?}`", e);
+            ("Failed to save alias!".to_string(), http::StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    };
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RepoGroupFormData {
+    pub name: String,
+    /// The urls of the repos, separated by commas or new lines.
+    pub urls: String,
+}
+
+/// Stores a named group of repos for the logged in user.
+/// Saving a group with an existing name replaces its repos.
+pub async fn repo_group_handler(session: Session, db: DbPool, info: web::Form<RepoGroupFormData>) -> impl Responder {
+
+    let user = match User::from_session(&session, &**db).await {
+        Some(v) => v,
+        None => return ("Not Logged In!".to_string(), http::StatusCode::UNAUTHORIZED),
+    };
+
+    let form_data = info.into_inner();
+    let group = RepoGroups {
+        user_fk: user.id.unwrap_or_default(),
+        name: form_data.name.trim().to_string(),
+        urls: form_data.urls
+            .split([',', '\n'])
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    };
+
+    if group.name.is_empty() || group.urls.is_empty() {
+        return ("A repo group needs a `name` and at least one url!".to_string(), http::StatusCode::BAD_REQUEST);
+    }
+
+    return match group.push_update(&**db).await {
+        Ok(_) => (format!("Saved repo group `{}` with {} repos!", group.name, group.urls.len()), http::StatusCode::OK),
+        Err(e) => {
+            warn!("Failed to save repo group with error: `{:?}`", e);
+            ("Failed to save repo group!".to_string(), http::StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    };
+}
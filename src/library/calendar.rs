@@ -6,6 +6,8 @@ use serde::{Deserialize, Serialize};
 pub struct CalendarValue {
     /// The title of the commit
     pub title: String,
+    /// The id of the commit.
+    pub commit: String,
     /// The amount of time from the start of the commit to the end.
     pub delta_t: Timestamp,
     /// The epoch timestamp for when the commit started.
@@ -15,7 +17,74 @@ pub struct CalendarValue {
     pub end: Timestamp,
     /// A flag, true if the value was projected, false if not.
     pub projected: bool,
+    /// A flag, true if the start was set manually (see `git::get_start_overrides()`.)
+    pub overridden: bool,
+    /// The UTC offset (in minutes) of the timezone the commit was made in.
+    pub offset_minutes: i32,
     /// The author of the commit
     pub author: String,
+    /// The co-authors of the commit (from `Co-authored-by:` trailers.)
+    pub co_authors: Vec<String>,
+    /// The url of the repository the commit is from.
+    pub repo: String,
+    /// The names of the analyzed branches (or refs) the commit is reachable from.
+    pub branches: Vec<String>,
+    /// The changes that were left out of the metrics (lockfiles, generated code, etc.)
+    pub excluded: ExcludedChanges,
+    /// If the commit is signed and if the signature was verified against the server's keyring.
+    pub signature: SignatureStatus,
+}
+
+/// The signature status of a commit.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SignatureStatus {
+    /// The commit has no signature.
+    #[default]
+    Unsigned,
+    /// The commit has a signature that couldn't be verified (e.g. the key isn't in the server's
+    /// keyring or the signature doesn't match.)
+    Signed,
+    /// The commit was signed by a key in the server's keyring.
+    Verified {
+        /// The user id (GPG) or principal (SSH) of the key.
+        signer: String,
+    },
+}
+
+/// A tag shown on the calendar as a marker (e.g. a release.)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Milestone {
+    /// The name of the tag.
+    pub tag: String,
+    /// The id of the commit the tag points to.
+    pub target: String,
+    /// The epoch timestamp of the tag. This is the tagger's date for annotated tags and the
+    /// commit's date for lightweight tags.
+    pub date: Timestamp,
+    /// The message of annotated tags.
+    pub message: Option<String>,
+    /// The url of the repository the tag is from.
+    pub repo: String,
+}
+
+/// The events and milestones of a report.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Report {
+    /// The work sessions (newest first.)
+    pub events: Vec<CalendarValue>,
+    /// The tags of the analyzed commits (newest first.)
+    pub milestones: Vec<Milestone>,
+}
+
+/// Counts of the changes in a commit that aren't used for making predictions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExcludedChanges {
+    /// The amount of excluded files changed.
+    pub files_changed: usize,
+    /// The amount of lines added to excluded files.
+    pub insertions: usize,
+    /// The amount of lines removed from excluded files.
+    pub deletions: usize,
 }
 
@@ -36,11 +36,62 @@ pub struct CliArgs {
     #[clap(long, action, default_value="false")]
     pub allow_local: bool,
 
+    /// A flag for allowing logged in users to set the start times of commits (see
+    /// `/api/overrides`.) The start times are written as git notes and pushed to the repo's remote.
+    #[clap(long, action, default_value="false")]
+    pub allow_note_writes: bool,
+
+    /// A directory that is scanned for local repos (e.g. `~/code`), can be repeated.
+    /// Only used with `--allow-local`, which doesn't allow any local repos without one.
+    #[clap(long)]
+    pub local_root: Vec<String>,
+
+    /// How many directories deep the local roots are scanned for repos.
+    #[clap(long, default_value="4")]
+    pub scan_depth: usize,
+
+    /// How long a scan of the local roots is cached for (in seconds.)
+    #[clap(long, default_value="300")]
+    pub scan_cache_seconds: u64,
+
     /// The path to the temp directory for the repos
     /// If set to none, the system tries to find the tmp directory from the path of the executable "./tmp".
     #[clap(long, default_value="./tmp")]
     pub tmp: String,
 
+    /// The maximum total size of the repos cloned into the tmp directory (in megabytes.)
+    /// The least recently used repos are deleted once it's exceeded.
+    #[clap(long, default_value="10240")]
+    pub cache_budget_mb: u64,
+
+    /// The maximum size of a single cloned repo (in megabytes.) Larger repos aren't analyzed.
+    #[clap(long, default_value="2048")]
+    pub max_repo_size_mb: u64,
+
+    /// How long a repo's lock file has to go without being refreshed before it's assumed to be
+    /// left over from a crashed process (in seconds.) Held locks are refreshed regularly.
+    #[clap(long, default_value="3600")]
+    pub repo_lock_stale_seconds: u64,
+
+    /// The maximum amount of background jobs (clones and analyses) that run at the same time.
+    /// Other jobs wait in the queue.
+    #[clap(long, default_value="4")]
+    pub max_jobs: usize,
+
+    /// How long the results of finished background jobs are kept for (in seconds.)
+    #[clap(long, default_value="600")]
+    pub job_retention_seconds: u64,
+
+    /// A GPG keyring of the public keys trusted for signing commits (e.g. from
+    /// `gpg --export > keyring.gpg`.) Commits signed by these keys are reported as verified.
+    #[clap(long)]
+    pub gpg_keyring: Option<String>,
+
+    /// An SSH allowed signers file (see `ssh-keygen -Y verify`) of the public keys trusted for
+    /// signing commits. Commits signed by these keys are reported as verified.
+    #[clap(long)]
+    pub ssh_allowed_signers: Option<String>,
+
     /// The path to where the static files are
     /// If set to none, the system tries to find the static directory from the path to the
     /// executable at "./static".
@@ -53,6 +104,16 @@ pub struct CliArgs {
     /// This value should always be Some(_) so it is safe to `.unwrap()` this value.
     project_location: Option<PathBuf>,
 
+    /// A flag for letting requests that aren't logged in use the server's ssh key and agent.
+    /// Without it the server's credentials are only used for logged in users.
+    #[clap(long, action, default_value="false")]
+    pub share_server_credentials: bool,
+
+    /// The secret the credentials users store are encrypted with. Users can't store credentials
+    /// without it and changing it makes the stored ones unreadable.
+    #[clap(long, env = "GIT_STATS_SECRETS_KEY", hide_env_values = true)]
+    pub secrets_key: Option<String>,
+
     /// The location of an ssh key for auth from the home directory.
     #[clap(short, long, default_value=".ssh/id_ed25519")]
     pub ssh_key: String,
@@ -61,6 +122,17 @@ pub struct CliArgs {
     #[clap(short, long, default_value="18000")]
     pub time_allowed: i64,
 
+    /// The maximum amount of commits that get analyzed per request.
+    /// Requests can ask for fewer commits but never more.
+    #[clap(short='m', long, default_value="100000")]
+    pub max_commits: usize,
+
+    /// Comma separated pathspecs of the files left out of the line count metrics by default
+    /// (requests can override these with `stats_exclude`.)
+    /// Files marked as `linguist-generated` or `linguist-vendored` are also left out.
+    #[clap(long, default_value="*.lock,*package-lock.json,*pnpm-lock.yaml,*go.sum,*.min.js,*.min.css,vendor/,*/vendor/*,node_modules/,*/node_modules/*")]
+    pub stats_exclude: String,
+
     /// The amount of commits to go back when cloning a repo.
     #[clap(short='d', long, default_value="5000")]
     pub clone_depth: i64,
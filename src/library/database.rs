@@ -3,17 +3,110 @@
 use actix_session::Session;
 use chrono::NaiveDateTime;
 use futures_util::future::ok;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use validator::Validate;
 use serde::Deserialize;
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use sqlx::{prelude::FromRow, Pool, Sqlite};
 
-use super::utils::UpdateResult;
+use git2::Oid;
+
+use super::{
+    calendar::ExcludedChanges,
+    git::{Credentials, IdentityAlias, SshKey},
+    secrets::SecretCipher,
+    stats::{CommitStats, StatsCache},
+    utils::UpdateResult,
+};
 
 /// The session signing key for this application (this might have to be randomized)
 pub const SESSION_USER_ID_KEY: &str = "user_id";
 
+/// The version of `schema.sql` (stored as the database's `user_version`.)
+/// Has to be increased with a step in `migrate()` whenever existing tables change.
+pub const SCHEMA_VERSION: i64 = 1;
+
+/// Upgrades tables created by an older `schema.sql` to the current one.
+/// Has to run before `schema.sql`, which only creates the tables that don't exist yet.
+pub async fn migrate(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+    let mut tx = pool.begin().await?;
+
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version").fetch_one(&mut *tx).await?;
+    debug!("Database schema version: {version} (current: {SCHEMA_VERSION})");
+
+    if version < 1 {
+
+        // The commit stats are only a cache, so tables from before they were keyed by the diff
+        // options are dropped (the unique key can't be altered) and get computed again
+        let commit_columns = table_columns("Commits", &mut tx).await?;
+        if !commit_columns.is_empty() && !commit_columns.iter().any(|v| v == "diff_options") {
+            info!("Dropping outdated `Commits` table!");
+            sqlx::query("DROP TABLE Commits").execute(&mut *tx).await?;
+        } else if !commit_columns.is_empty() {
+            for (column, definition) in [
+                ("excluded_files", "excluded_files INTEGER DEFAULT 0 NOT NULL"),
+                ("excluded_insertions", "excluded_insertions INTEGER DEFAULT 0 NOT NULL"),
+                ("excluded_deletions", "excluded_deletions INTEGER DEFAULT 0 NOT NULL"),
+                ("files_renamed", "files_renamed INTEGER DEFAULT 0 NOT NULL"),
+            ] {
+                if !commit_columns.iter().any(|v| v == column) {
+                    info!("Adding column `{column}` to the `Commits` table!");
+                    sqlx::query(&format!("ALTER TABLE Commits ADD COLUMN {definition}")).execute(&mut *tx).await?;
+                }
+            }
+        }
+
+        // The owner and host of repos used to be required and the url didn't exist, which needs
+        // the table to be rebuilt (SQLite can't drop `NOT NULL` constraints)
+        let repo_columns = table_columns("Repos", &mut tx).await?;
+        if !repo_columns.is_empty() && !repo_columns.iter().any(|v| v == "url") {
+            info!("Rebuilding outdated `Repos` table!");
+            sqlx::query("
+                CREATE TABLE Repos_new (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    owner_fk INTEGER,
+                    host_fk INTEGER,
+                    repo_name TEXT NOT NULL,
+                    url TEXT UNIQUE,
+                    FOREIGN KEY (owner_fk)
+                        REFERENCES Users(id)
+                            ON UPDATE CASCADE
+                            ON DELETE CASCADE,
+                    FOREIGN KEY (host_fk)
+                        REFERENCES Hosts(id)
+                            ON UPDATE CASCADE
+                            ON DELETE CASCADE
+                );
+                INSERT INTO Repos_new (id, owner_fk, host_fk, repo_name) SELECT id, owner_fk, host_fk, repo_name FROM Repos;
+                DROP TABLE Repos;
+                ALTER TABLE Repos_new RENAME TO Repos;
+                ")
+                .execute(&mut *tx)
+                .await?;
+        }
+
+    }
+
+    sqlx::query(&format!("PRAGMA user_version = {SCHEMA_VERSION}")).execute(&mut *tx).await?;
+    tx.commit().await?;
+
+    return Ok(());
+
+}
+
+/// Gets the names of the columns of a table (empty if the table doesn't exist.)
+async fn table_columns(table: &str, tx: &mut sqlx::Transaction<'_, Sqlite>) -> Result<Vec<String>, sqlx::Error> {
+
+    let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info($1)")
+        .bind(table)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    return Ok(columns.into_iter().map(|(v,)| v).collect());
+
+}
+
 /// Struct holding database connection information
 pub struct Database {
     url: String,
@@ -299,6 +392,355 @@ impl User {
 
 }
 
+/// A struct that represents the credentials a user stored for a git host.
+#[derive(Debug, FromRow, Deserialize)]
+pub struct UserCredentials {
+    /// The id of the user the credentials belong to.
+    pub user_fk: i64,
+    /// The host the credentials are used for (e.g. `github.com`.)
+    pub host: String,
+    /// The username to authenticate as.
+    pub username: Option<String>,
+    /// A personal access token for HTTPS remotes.
+    pub token: Option<String>,
+    /// A private ssh key.
+    pub ssh_key: Option<String>,
+    /// The passphrase of the private ssh key.
+    pub ssh_passphrase: Option<String>,
+}
+
+impl UserCredentials {
+
+    /// Gets the credentials a user stored for a host.
+    /// Secrets that can't be decrypted are left unset.
+    pub async fn from_user_and_host(user_fk: i64, host: &str, cipher: &SecretCipher, pool: &Pool<Sqlite>) -> Option<Self> {
+
+        let stored: Option<Self> = match sqlx::query_as("SELECT user_fk, host, username, token, ssh_key, ssh_passphrase
+            FROM UserCredentials WHERE user_fk = $1 AND host = $2 LIMIT 1")
+            .bind(user_fk)
+            .bind(host)
+            .fetch_optional(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get credentials for user: `{}` and host: `{}` with error: `{:?}`", user_fk, host, e);
+                None
+            },
+        };
+
+        let decrypt = |v: Option<String>| v.and_then(|v| cipher.decrypt(&v));
+
+        return stored.map(|v| Self {
+            token: decrypt(v.token),
+            ssh_key: decrypt(v.ssh_key),
+            ssh_passphrase: decrypt(v.ssh_passphrase),
+            ..v
+        });
+
+    }
+
+    /// Adds the credentials to the database, replacing the user's previous credentials for the
+    /// same host.
+    /// The token, ssh key and passphrase are encrypted with `cipher`, nothing is stored if it
+    /// doesn't have a secret.
+    pub async fn push_update(&self, cipher: &SecretCipher, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+        if !cipher.is_enabled() {
+            return Err(sqlx::Error::Protocol("Can't store credentials without a secrets key".to_string()));
+        }
+
+        let encrypt = |v: &Option<String>| v.as_ref().and_then(|v| cipher.encrypt(v));
+
+        sqlx::query("INSERT INTO UserCredentials (user_fk, host, username, token, ssh_key, ssh_passphrase)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_fk, host) DO UPDATE SET
+                username = excluded.username,
+                token = excluded.token,
+                ssh_key = excluded.ssh_key,
+                ssh_passphrase = excluded.ssh_passphrase,
+                last_modified = CURRENT_TIMESTAMP
+            ;
+            ")
+            .bind(self.user_fk)
+            .bind(&self.host)
+            .bind(&self.username)
+            .bind(encrypt(&self.token))
+            .bind(encrypt(&self.ssh_key))
+            .bind(encrypt(&self.ssh_passphrase))
+            .execute(pool)
+            .await?;
+
+        return Ok(());
+
+    }
+
+    /// Encrypts the credentials that were stored before encryption was added.
+    /// Returns the amount of credentials that were encrypted.
+    pub async fn encrypt_stored(cipher: &SecretCipher, pool: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+
+        if !cipher.is_enabled() {
+            return Ok(0);
+        }
+
+        let stored: Vec<Self> = sqlx::query_as("SELECT user_fk, host, username, token, ssh_key, ssh_passphrase FROM UserCredentials")
+            .fetch_all(pool)
+            .await?;
+
+        let mut encrypted = 0;
+        for credentials in stored {
+            let is_plaintext = [&credentials.token, &credentials.ssh_key, &credentials.ssh_passphrase]
+                .into_iter()
+                .flatten()
+                .any(|v| !SecretCipher::is_encrypted(v));
+
+            if !is_plaintext {
+                continue;
+            }
+
+            // Values that are already encrypted are decrypted first so they aren't encrypted twice
+            let decrypt = |v: &Option<String>| match v {
+                Some(v) => cipher.decrypt(v).map(Some),
+                None => Some(None),
+            };
+            let (Some(token), Some(ssh_key), Some(ssh_passphrase)) = (decrypt(&credentials.token), decrypt(&credentials.ssh_key), decrypt(&credentials.ssh_passphrase)) else {
+                warn!("Can't encrypt the credentials of user: `{}` for host: `{}`", credentials.user_fk, credentials.host);
+                continue;
+            };
+
+            Self { token, ssh_key, ssh_passphrase, ..credentials }.push_update(cipher, pool).await?;
+            encrypted += 1;
+        }
+
+        return Ok(encrypted);
+
+    }
+
+    /// Adds the stored credentials to a set of credentials.
+    /// Stored values take priority over the values already in `credentials`.
+    pub fn apply_to(&self, credentials: &mut Credentials) {
+
+        if self.username.is_some() {
+            credentials.username = self.username.clone();
+        }
+
+        if self.token.is_some() {
+            credentials.token = self.token.clone();
+        }
+
+        if let Some(key) = &self.ssh_key {
+            credentials.ssh_key = Some(SshKey::Memory(key.clone()));
+            credentials.ssh_passphrase = self.ssh_passphrase.clone();
+        }
+
+    }
+
+}
+
+/// A struct that represents an identity alias a user added.
+#[derive(Debug, FromRow, Deserialize)]
+pub struct IdentityAliases {
+    /// The id of the user the alias belongs to.
+    pub user_fk: i64,
+    /// The name to use instead of the alias's name.
+    pub canonical_name: Option<String>,
+    /// The email to use instead of the alias's email.
+    pub canonical_email: Option<String>,
+    /// The name of the alias.
+    pub alias_name: Option<String>,
+    /// The email of the alias.
+    pub alias_email: String,
+}
+
+impl IdentityAliases {
+
+    /// Gets every alias a user added.
+    /// Returns no aliases if the query fails.
+    pub async fn from_user(user_fk: i64, pool: &Pool<Sqlite>) -> Vec<IdentityAlias> {
+
+        let aliases: Vec<IdentityAliases> = match sqlx::query_as("SELECT user_fk, canonical_name, canonical_email, alias_name, alias_email
+            FROM IdentityAliases WHERE user_fk = $1")
+            .bind(user_fk)
+            .fetch_all(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get aliases for user: `{}` with error: `{:?}`", user_fk, e);
+                return Vec::new();
+            },
+        };
+
+        return aliases
+            .into_iter()
+            .map(|v| IdentityAlias {
+                canonical_name: v.canonical_name,
+                canonical_email: v.canonical_email,
+                alias_name: v.alias_name.filter(|v| !v.is_empty()),
+                alias_email: v.alias_email,
+            })
+            .collect();
+
+    }
+
+    /// Adds the alias to the database, replacing an alias of the same identity.
+    pub async fn push_update(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+        sqlx::query("INSERT INTO IdentityAliases (user_fk, canonical_name, canonical_email, alias_name, alias_email)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_fk, alias_name, alias_email) DO UPDATE SET
+                canonical_name = excluded.canonical_name,
+                canonical_email = excluded.canonical_email
+            ;
+            ")
+            .bind(self.user_fk)
+            .bind(&self.canonical_name)
+            .bind(&self.canonical_email)
+            .bind(self.alias_name.as_deref().unwrap_or_default())
+            .bind(&self.alias_email)
+            .execute(pool)
+            .await?;
+
+        return Ok(());
+
+    }
+
+}
+
+/// A named group of repos a user analyzes together.
+#[derive(Debug, Clone)]
+pub struct RepoGroups {
+    /// The id of the user the group belongs to.
+    pub user_fk: i64,
+    /// The name of the group.
+    pub name: String,
+    /// The urls of the repos in the group.
+    pub urls: Vec<String>,
+}
+
+impl RepoGroups {
+
+    /// Gets a group of a user by its name.
+    /// Returns `None` if the group doesn't exist (or the query fails.)
+    pub async fn from_user_and_name(user_fk: i64, name: &str, pool: &Pool<Sqlite>) -> Option<Self> {
+
+        let urls: Vec<(String,)> = match sqlx::query_as("SELECT url FROM RepoGroups WHERE user_fk = $1 AND name = $2 ORDER BY id")
+            .bind(user_fk)
+            .bind(name)
+            .fetch_all(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get repo group: `{}` for user: `{}` with error: `{:?}`", name, user_fk, e);
+                return None;
+            },
+        };
+
+        if urls.is_empty() {
+            return None;
+        }
+
+        return Some(Self {
+            user_fk,
+            name: name.to_string(),
+            urls: urls.into_iter().map(|v| v.0).collect(),
+        });
+
+    }
+
+    /// Adds the group to the database, replacing the repos of a group with the same name.
+    pub async fn push_update(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM RepoGroups WHERE user_fk = $1 AND name = $2")
+            .bind(self.user_fk)
+            .bind(&self.name)
+            .execute(&mut *tx)
+            .await?;
+
+        for url in &self.urls {
+            sqlx::query("INSERT INTO RepoGroups (user_fk, name, url) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+                .bind(self.user_fk)
+                .bind(&self.name)
+                .bind(url)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        return Ok(());
+
+    }
+
+}
+
+/// An entry in the index of repos cloned into the tmp directory (see `RepoCache`.)
+#[derive(Debug, Clone, FromRow)]
+pub struct CachedRepos {
+    /// The directory the repo is cloned into.
+    pub path: String,
+    /// The url the repo was cloned from.
+    pub url: String,
+    /// The size of the clone in bytes.
+    pub size_bytes: i64,
+    /// The epoch timestamp of the last time the repo was used.
+    pub last_accessed: i64,
+}
+
+impl CachedRepos {
+
+    /// Gets every cached repo, starting with the least recently used.
+    /// Returns no repos if the query fails.
+    pub async fn all(pool: &Pool<Sqlite>) -> Vec<Self> {
+
+        return match sqlx::query_as("SELECT path, url, size_bytes, last_accessed FROM RepoCache ORDER BY last_accessed ASC")
+            .fetch_all(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get the repo cache index with error: `{:?}`", e);
+                Vec::new()
+            },
+        };
+
+    }
+
+    /// Adds the repo to the index, updating its size and last access if it's already indexed.
+    pub async fn push_update(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+        sqlx::query("INSERT INTO RepoCache (path, url, size_bytes, last_accessed)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (path) DO UPDATE SET
+                url = excluded.url,
+                size_bytes = excluded.size_bytes,
+                last_accessed = excluded.last_accessed
+            ;
+            ")
+            .bind(&self.path)
+            .bind(&self.url)
+            .bind(self.size_bytes)
+            .bind(self.last_accessed)
+            .execute(pool)
+            .await?;
+
+        return Ok(());
+
+    }
+
+    /// Removes a repo from the index.
+    pub async fn remove(path: &str, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+
+        sqlx::query("DELETE FROM RepoCache WHERE path = $1")
+            .bind(path)
+            .execute(pool)
+            .await?;
+
+        return Ok(());
+
+    }
+
+}
+
 pub struct Hosts {
     id: i64,
     host_name: String,
@@ -306,22 +748,155 @@ pub struct Hosts {
 
 pub struct Repos {
     id: i64,
-    owner_fk: i64,
-    host_fk: i64,
+    owner_fk: Option<i64>,
+    host_fk: Option<i64>,
     repo_name: String,
+    url: Option<String>,
 }
 
+impl Repos {
+
+    /// Gets the id of the repo analyzed from `url`.
+    /// Adds the repo to the database if it hasn't been analyzed before.
+    pub async fn id_from_url(url: &str, repo_name: &str, pool: &Pool<Sqlite>) -> Option<i64> {
+
+        if let Err(e) = sqlx::query("INSERT INTO Repos (repo_name, url) VALUES ($1, $2) ON CONFLICT (url) DO NOTHING")
+            .bind(repo_name)
+            .bind(url)
+            .execute(pool)
+            .await {
+            warn!("Failed to add repo: `{}` to database! Error: `{:?}`", url, e);
+            return None;
+        }
+
+        let (id,): (i64,) = match sqlx::query_as("SELECT id FROM Repos WHERE url = $1 LIMIT 1")
+            .bind(url)
+            .fetch_one(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get repo by url: `{}` with error: `{:?}`", url, e);
+                return None;
+            },
+        };
+
+        return Some(id);
+
+    }
+
+}
+
+/// A struct that represents the cached stats of a commit.
+#[derive(Debug, FromRow)]
 pub struct Commits {
     id: i64,
     repo_fk: i64,
-    user_fk: i64,
-    committer_username: String,
-    committer_email: String,
+    user_fk: Option<i64>,
+    oid: String,
+    diff_options: String,
+    author_name: String,
+    author_email: String,
     timestamp: i64,
+    files_changed: i64,
+    files_renamed: i64,
+    insertions: i64,
+    deletions: i64,
+    excluded_files: i64,
+    excluded_insertions: i64,
+    excluded_deletions: i64,
     date_created: NaiveDateTime,
     last_modified: NaiveDateTime,
-    projected_start: NaiveDateTime,
-    set_start: NaiveDateTime,
+    projected_start: Option<NaiveDateTime>,
+    set_start: Option<NaiveDateTime>,
+}
+
+impl Commits {
+
+    /// Gets the stats of every cached commit from a repo that were computed with the same diff
+    /// options (see `AnalysisOptions::stats_key()`.)
+    /// Returns an empty cache if the query fails.
+    pub async fn stats_cache_from_repo(repo_fk: i64, diff_options: &str, pool: &Pool<Sqlite>) -> StatsCache {
+
+        let commits: Vec<Commits> = match sqlx::query_as("SELECT * FROM Commits WHERE repo_fk = $1 AND diff_options = $2")
+            .bind(repo_fk)
+            .bind(diff_options)
+            .fetch_all(pool)
+            .await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get cached commits for repo: `{}` with error: `{:?}`", repo_fk, e);
+                return StatsCache::new();
+            },
+        };
+
+        debug!("Found {} cached commits for repo: `{}`", commits.len(), repo_fk);
+
+        return commits
+            .into_iter()
+            .filter_map(|v| v.to_stats())
+            .collect();
+
+    }
+
+    /// Adds the stats of commits to a repo's cache.
+    /// Returns the amount of commits added.
+    pub async fn insert_stats<'a>(repo_fk: i64, diff_options: &str, stats: impl Iterator<Item = &'a CommitStats>, pool: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+
+        let mut tx = pool.begin().await?;
+        let mut rows_affected = 0;
+
+        for commit in stats {
+            rows_affected += sqlx::query("INSERT INTO Commits
+                (repo_fk, oid, diff_options, author_name, author_email, timestamp, files_changed, files_renamed, insertions, deletions, excluded_files, excluded_insertions, excluded_deletions)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                ON CONFLICT (repo_fk, oid, diff_options) DO NOTHING
+                ")
+                .bind(repo_fk)
+                .bind(commit.oid.to_string())
+                .bind(diff_options)
+                .bind(&commit.author_name)
+                .bind(&commit.author_email)
+                .bind(commit.timestamp)
+                .bind(commit.files_changed as i64)
+                .bind(commit.files_renamed as i64)
+                .bind(commit.insertions as i64)
+                .bind(commit.deletions as i64)
+                .bind(commit.excluded.files_changed as i64)
+                .bind(commit.excluded.insertions as i64)
+                .bind(commit.excluded.deletions as i64)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+
+        tx.commit().await?;
+
+        return Ok(rows_affected);
+
+    }
+
+    /// Converts the database row into commit stats.
+    /// Returns `None` if the stored oid isn't valid.
+    pub fn to_stats(&self) -> Option<CommitStats> {
+
+        return Some(CommitStats {
+            oid: Oid::from_str(&self.oid).ok()?,
+            author_name: self.author_name.clone(),
+            author_email: self.author_email.clone(),
+            timestamp: self.timestamp,
+            files_changed: self.files_changed as usize,
+            files_renamed: self.files_renamed as usize,
+            insertions: self.insertions as usize,
+            deletions: self.deletions as usize,
+            excluded: ExcludedChanges {
+                files_changed: self.excluded_files as usize,
+                insertions: self.excluded_insertions as usize,
+                deletions: self.excluded_deletions as usize,
+            },
+        });
+
+    }
+
 }
 
 pub struct Reports {
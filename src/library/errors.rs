@@ -4,7 +4,7 @@ use actix_web::{http::StatusCode, HttpResponse, Responder, ResponseError};
 use serde::{Deserialize, Serialize};
 
 /// AppErrors is the error handling struct for this library.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppError {
     /// This is the internal cause of the error. (Doesn't get displayed to the user)
     pub cause: Option<String>,
@@ -0,0 +1,212 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+/// A filter for the people (authors or committers) behind commits.
+/// An empty filter matches everyone, otherwise an identity matches if any of the values match.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityFilter {
+    /// Emails to match exactly (ignoring case.)
+    pub emails: Vec<String>,
+    /// Names to match exactly.
+    pub names: Vec<String>,
+    /// Patterns to match against the identity formatted as `Name <email>`.
+    pub patterns: Vec<Regex>,
+}
+
+impl IdentityFilter {
+
+    /// Creates a filter from a comma separated list of names and emails and an optional regular
+    /// expression. Values containing an `@` are treated as emails.
+    /// ```rust
+    /// # use git_stats_web::filter::IdentityFilter;
+    /// let filter = IdentityFilter::new(Some("Alice, bob@example.com"), Some(r"^Carol ")).unwrap();
+    /// assert!(filter.matches("Alice", "alice@example.com"));
+    /// assert!(filter.matches("Robert", "Bob@Example.com"));
+    /// assert!(filter.matches("Carol", "carol@example.com"));
+    /// assert!(!filter.matches("Dave", "dave@example.com"));
+    ///
+    /// // Empty filters match everyone
+    /// assert!(IdentityFilter::new(None, None).unwrap().matches("Dave", "dave@example.com"));
+    /// ```
+    pub fn new(values: Option<&str>, pattern: Option<&str>) -> Result<Self, regex::Error> {
+
+        let mut filter = Self::default();
+
+        for value in values.unwrap_or_default().split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            match value.contains('@') {
+                true => filter.emails.push(value.to_lowercase()),
+                false => filter.names.push(value.to_string()),
+            }
+        }
+
+        if let Some(pattern) = pattern.filter(|v| !v.is_empty()) {
+            filter.patterns.push(Regex::new(pattern)?);
+        }
+
+        return Ok(filter);
+
+    }
+
+    /// Returns true if the filter doesn't filter anything.
+    pub fn is_empty(&self) -> bool {
+        return self.emails.is_empty() && self.names.is_empty() && self.patterns.is_empty();
+    }
+
+    /// Checks if an identity passes the filter.
+    pub fn matches(&self, name: &str, email: &str) -> bool {
+
+        if self.is_empty() {
+            return true;
+        }
+
+        if self.emails.iter().any(|v| v.eq_ignore_ascii_case(email)) {
+            return true;
+        }
+
+        if self.names.iter().any(|v| v == name) {
+            return true;
+        }
+
+        let identity = format!("{name} <{email}>");
+        return self.patterns.iter().any(|v| v.is_match(&identity));
+
+    }
+
+}
+
+/// Pathspecs limiting which files of a commit get counted.
+/// Commits that don't touch any of the counted files aren't reported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathFilter {
+    /// Only files matching these pathspecs are counted. Every file is counted if empty.
+    pub include: Vec<String>,
+    /// Files matching these pathspecs aren't counted.
+    pub exclude: Vec<String>,
+}
+
+impl PathFilter {
+
+    /// Creates a filter from comma separated lists of pathspecs.
+    /// ```rust
+    /// # use git_stats_web::filter::PathFilter;
+    /// let filter = PathFilter::new(Some("server/, docs/*.md"), Some("server/vendor"));
+    /// assert_eq!(filter.include, vec!["server/".to_string(), "docs/*.md".to_string()]);
+    /// assert_eq!(filter.exclude, vec!["server/vendor".to_string()]);
+    /// assert!(PathFilter::new(None, Some("")).is_empty());
+    /// ```
+    pub fn new(include: Option<&str>, exclude: Option<&str>) -> Self {
+
+        let split = |v: Option<&str>| v
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<String>>();
+
+        return Self {
+            include: split(include),
+            exclude: split(exclude),
+        };
+
+    }
+
+    /// Returns true if the filter doesn't filter anything.
+    pub fn is_empty(&self) -> bool {
+        return self.include.is_empty() && self.exclude.is_empty();
+    }
+
+    /// Gets a key that identifies the filter (used for caching stats computed with it.)
+    /// The key of an empty filter is an empty string.
+    pub fn cache_key(&self) -> String {
+
+        if self.is_empty() {
+            return String::new();
+        }
+
+        return format!("include={};exclude={}", self.include.join(","), self.exclude.join(","));
+
+    }
+
+}
+
+/// Files whose changes are left out of the line count metrics (lockfiles, vendored or generated
+/// code.) Unlike `PathFilter::exclude` the commits are still reported and the excluded changes
+/// are counted separately.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsExclusion {
+    /// Pathspecs of the excluded files. Note that `*` also matches `/` (`*.lock` matches
+    /// `a/Cargo.lock`.)
+    pub patterns: Vec<String>,
+    /// Also excludes files marked with the `linguist-generated` or `linguist-vendored` attributes
+    /// in the `.gitattributes` of the HEAD commit. Setting these to `false` counts a file even if
+    /// a pattern matches it.
+    pub linguist: bool,
+}
+
+impl StatsExclusion {
+
+    /// Creates an exclusion from a comma separated list of pathspecs.
+    /// ```rust
+    /// # use git_stats_web::filter::StatsExclusion;
+    /// let exclusion = StatsExclusion::new("*.lock, vendor/", true);
+    /// assert_eq!(exclusion.patterns, vec!["*.lock".to_string(), "vendor/".to_string()]);
+    /// assert!(StatsExclusion::new("", false).is_empty());
+    /// ```
+    pub fn new(patterns: &str, linguist: bool) -> Self {
+        return Self {
+            patterns: patterns
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect(),
+            linguist,
+        };
+    }
+
+    /// Returns true if nothing gets excluded.
+    pub fn is_empty(&self) -> bool {
+        return self.patterns.is_empty() && !self.linguist;
+    }
+
+    /// Gets a key that identifies the exclusion (used for caching stats computed with it.)
+    /// The key of an empty exclusion is an empty string.
+    pub fn cache_key(&self) -> String {
+
+        if self.is_empty() {
+            return String::new();
+        }
+
+        return format!("stats_exclude={};linguist={}", self.patterns.join(","), self.linguist);
+
+    }
+
+}
+
+/// Which commits the prediction model gets trained on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PredictionScope {
+    /// Trains on every commit, even ones removed by the filters.
+    #[default]
+    Team,
+    /// Only trains on the commits that pass the filters.
+    Filtered,
+}
+
+impl FromStr for PredictionScope {
+    type Err = String;
+
+    /// ```rust
+    /// # use git_stats_web::filter::PredictionScope;
+    /// assert_eq!("team".parse(), Ok(PredictionScope::Team));
+    /// assert_eq!("author".parse(), Ok(PredictionScope::Filtered));
+    /// assert!("someone".parse::<PredictionScope>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.trim().to_lowercase().as_str() {
+            "team" | "all" => Ok(Self::Team),
+            "author" | "filtered" => Ok(Self::Filtered),
+            v => Err(format!("Unknown prediction scope: `{v}` (expected `team` or `author`)")),
+        };
+    }
+}
@@ -1,72 +1,347 @@
-use git2::{Commit, FetchOptions, Progress, RemoteCallbacks, Repository};
-use std::{path::Path, sync::Arc};
-use anyhow::{Context, Result};
+use actix_web::http::StatusCode;
+use chrono::{DateTime, FixedOffset};
+use git2::{message_trailers_strs, BranchType, Commit, Cred, CredentialType, ErrorClass, ErrorCode, FetchOptions, FetchPrune, Mailmap, ObjectType, Oid, Progress, PushOptions, Reference, RemoteCallbacks, Repository, Revwalk, Signature, Sort, Tree};
+use std::{cmp::Reverse, collections::{HashMap, HashSet}, env, path::{Path, PathBuf}, str::FromStr};
 use log::{debug, warn};
 
-use super::cli::CliArgs;
+use super::{aliases::Timestamp, cache::MEGABYTE, calendar::Milestone, cli::CliArgs, errors::AppError};
 
-/// The callback that just displays the object number.
-fn print_callback(v: Progress) -> bool {
-    debug!("Received Objects: #{:?}", v.received_objects());
-    true
+/// Represents which references a commit walk should start from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RefSelection {
+    /// Only the commit `HEAD` points to.
+    #[default]
+    Head,
+    /// Every local and remote branch.
+    All,
+    /// Every local branch (`refs/heads/*`).
+    Local,
+    /// Every remote tracking branch (`refs/remotes/*`).
+    Remote,
+    /// Every reference matching a glob such as `refs/heads/feature/*`.
+    Glob(String),
+    /// A list of branch or reference names such as `main` or `origin/dev`.
+    Names(Vec<String>),
+}
+
+impl From<&str> for RefSelection {
+    /// Parses a selection from a get request parameter.
+    /// ```rust
+    /// # use git_stats_web::git::RefSelection;
+    /// assert_eq!(RefSelection::from("all"), RefSelection::All);
+    /// assert_eq!(RefSelection::from("refs/heads/feature/*"), RefSelection::Glob("refs/heads/feature/*".to_string()));
+    /// assert_eq!(RefSelection::from("main, dev"), RefSelection::Names(vec!["main".to_string(), "dev".to_string()]));
+    /// ```
+    fn from(value: &str) -> Self {
+        let value = value.trim();
+
+        return match value.to_lowercase().as_str() {
+            "" | "head" => Self::Head,
+            "all" => Self::All,
+            "local" => Self::Local,
+            "remote" => Self::Remote,
+            _ if value.contains(['*', '?', '[']) => Self::Glob(value.to_string()),
+            _ => Self::Names(
+                value
+                    .split(',')
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect()
+            ),
+        };
+    }
+}
+
+/// How merge commits are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Diffs merges against their first parent (like any other commit.)
+    #[default]
+    FirstParent,
+    /// Leaves merges out of the report and the predictions.
+    Skip,
+    /// Reports merges as markers that took no time and aren't used for predictions.
+    Marker,
+    /// Diffs merges against the merge base of their parents.
+    MergeBase,
+}
+
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    /// ```rust
+    /// # use git_stats_web::git::MergePolicy;
+    /// assert_eq!("skip".parse(), Ok(MergePolicy::Skip));
+    /// assert_eq!("merge-base".parse(), Ok(MergePolicy::MergeBase));
+    /// assert!("squash".parse::<MergePolicy>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.trim().to_lowercase().replace('-', "_").as_str() {
+            "first_parent" => Ok(Self::FirstParent),
+            "skip" => Ok(Self::Skip),
+            "marker" => Ok(Self::Marker),
+            "merge_base" => Ok(Self::MergeBase),
+            v => Err(format!("Unknown merge policy: `{v}` (expected `first_parent`, `skip`, `marker` or `merge_base`)")),
+        };
+    }
+}
+
+/// Gets the tree a commit's changes are diffed against.
+/// Root commits are diffed against the empty tree (`None`.)
+/// Merges are diffed against the merge base of their parents with `MergePolicy::MergeBase`
+/// (falling back to the first parent for unrelated histories) and against their first parent
+/// otherwise.
+pub fn get_base_tree<'repo>(repo: &'repo Repository, commit: &Commit<'repo>, policy: MergePolicy) -> Result<Option<Tree<'repo>>, git2::Error> {
+
+    if commit.parent_count() == 0 {
+        return Ok(None);
+    }
+
+    if commit.parent_count() > 1 && policy == MergePolicy::MergeBase {
+        let parents = commit.parent_ids().collect::<Vec<Oid>>();
+
+        match repo.merge_base_many(&parents) {
+            Ok(base) => return Ok(Some(repo.find_commit(base)?.tree()?)),
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                debug!("Merge `{}` has no merge base, diffing against its first parent", commit.id());
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(Some(commit.parent(0)?.tree()?));
+
+}
+
+/// The notes ref that start time overrides are stored in.
+pub const START_NOTES_REF: &str = "refs/notes/git-stats";
+
+/// The config key set on cached clones that were fetched with credentials.
+const PRIVATE_CONFIG_KEY: &str = "gitstats.private";
+
+/// The refspec for fetching and pushing the start time overrides.
+const START_NOTES_REFSPEC: &str = "+refs/notes/git-stats:refs/notes/git-stats";
+
+/// The private ssh key used for authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SshKey {
+    /// A path to a private key file on the server.
+    File(PathBuf),
+    /// The contents of a private key (e.g. one stored for a user.)
+    Memory(String),
+}
+
+/// The credentials that can be used for fetching private repos.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// The username to authenticate as. Falls back to the username from the url (or `git`.)
+    pub username: Option<String>,
+    /// A private ssh key.
+    pub ssh_key: Option<SshKey>,
+    /// The passphrase for the private ssh key.
+    pub ssh_passphrase: Option<String>,
+    /// Set if keys from the ssh agent should be tried.
+    pub use_ssh_agent: bool,
+    /// A personal access token for HTTPS remotes.
+    pub token: Option<String>,
+}
+
+/// Keeps track of which credentials have been tried while authenticating.
+/// libgit2 keeps asking for credentials until authentication succeeds so each method is only
+/// tried once.
+#[derive(Debug, Default)]
+pub struct CredentialAttempts {
+    ssh_agent: bool,
+    ssh_key: bool,
+    token: bool,
+    /// Set once every applicable credential has been tried.
+    pub exhausted: bool,
+}
+
+impl CredentialAttempts {
+    /// Returns true if any credential was handed to the remote.
+    pub fn used(&self) -> bool {
+        return self.ssh_agent || self.ssh_key || self.token;
+    }
+}
+
+impl Credentials {
+
+    /// Creates the server wide credentials from the CLI arguments.
+    /// The ssh key is only used if it exists.
+    /// These can read every repo the server's key can, so they should only be used for logged in
+    /// users (or with `--share-server-credentials`.)
+    pub fn from_args(args: &CliArgs) -> Self {
+
+        let ssh_key = env::var("HOME")
+            .map(|home| Path::new(&home).join(&args.ssh_key))
+            .ok()
+            .filter(|path| path.is_file())
+            .map(SshKey::File);
+
+        return Self {
+            ssh_key,
+            use_ssh_agent: true,
+            ..Self::default()
+        };
+
+    }
+
+    /// Gets the next credential to try for a remote.
+    /// Returns an error once every credential that's allowed has been tried.
+    /// ```rust
+    /// # use git_stats_web::git::{Credentials, CredentialAttempts};
+    /// # use git2::CredentialType;
+    /// let credentials = Credentials {
+    ///     token: Some("my-token".to_string()),
+    ///     ..Credentials::default()
+    /// };
+    /// let mut attempts = CredentialAttempts::default();
+    ///
+    /// // The token gets tried once
+    /// assert!(credentials.next_credential(&mut attempts, None, CredentialType::USER_PASS_PLAINTEXT).is_ok());
+    /// assert!(credentials.next_credential(&mut attempts, None, CredentialType::USER_PASS_PLAINTEXT).is_err());
+    /// assert!(attempts.exhausted);
+    ///
+    /// // There is no ssh key to try
+    /// let mut attempts = CredentialAttempts::default();
+    /// assert!(credentials.next_credential(&mut attempts, Some("git"), CredentialType::SSH_KEY).is_err());
+    /// ```
+    pub fn next_credential(&self, attempts: &mut CredentialAttempts, username_from_url: Option<&str>, allowed_types: CredentialType) -> Result<Cred, git2::Error> {
+
+        let username = self.username.as_deref()
+            .or(username_from_url)
+            .unwrap_or("git");
+
+        if allowed_types.is_username() {
+            return Cred::username(username);
+        }
+
+        if allowed_types.is_ssh_key() {
+
+            if self.use_ssh_agent && !attempts.ssh_agent {
+                attempts.ssh_agent = true;
+                debug!("Trying ssh agent for user: `{username}`");
+                if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+
+            if let (Some(key), false) = (&self.ssh_key, attempts.ssh_key) {
+                attempts.ssh_key = true;
+                debug!("Trying ssh key for user: `{username}`");
+                return match key {
+                    SshKey::File(path) => Cred::ssh_key(username, None, path, self.ssh_passphrase.as_deref()),
+                    SshKey::Memory(key) => Cred::ssh_key_from_memory(username, None, key, self.ssh_passphrase.as_deref()),
+                };
+            }
+
+        }
+
+        if allowed_types.is_user_pass_plaintext() {
+            if let (Some(token), false) = (&self.token, attempts.token) {
+                attempts.token = true;
+                debug!("Trying access token for user: `{username}`");
+                return Cred::userpass_plaintext(username, token);
+            }
+        }
+
+        attempts.exhausted = true;
+        return Err(git2::Error::new(ErrorCode::Auth, ErrorClass::Callback, "No more credentials to try"));
+
+    }
+
+}
+
+/// Returns true if a git error was caused by failing to authenticate.
+fn is_auth_error(e: &git2::Error) -> bool {
+    return e.code() == ErrorCode::Auth
+        || e.message().to_lowercase().contains("authentication");
 }
 
 /// Function for cloning a repo
 /// Returns error if it can't clone the repo.
-pub fn fetch_repo(ssh_url: &str, out_dir: &Path, args: Arc<CliArgs>) -> Result<Repository> {
+/// Can clone from any url libgit2 supports, including `file://` urls.
+/// `transfer_progress` is called as objects are downloaded from the remote.
+/// The fetch is cancelled (with a 413 error) once more than `max_size` bytes were downloaded.
+/// ```rust
+/// # use git_stats_web::git::{fetch_repo, Credentials};
+/// # use git2::{Repository, Signature};
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let tmp = dir.path();
+/// // Creates a repo to stand in for a remote
+/// let source = Repository::init(tmp.join("source")).unwrap();
+/// let signature = Signature::now("Alice", "alice@example.com").unwrap();
+/// let tree = source.find_tree(source.index().unwrap().write_tree().unwrap()).unwrap();
+/// let oid = source.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+///
+/// let url = format!("file://{}", tmp.join("source").display());
+/// let mut received_objects = 0;
+/// let repo = fetch_repo(&url, &tmp.join("clone"), &Credentials::default(), None, &mut |v| received_objects = v.received_objects()).unwrap();
+/// assert_eq!(repo.head().unwrap().target(), Some(oid));
+/// assert!(received_objects > 0);
+///
+/// // `HEAD` stays on the remote's default branch when other branches are fetched
+/// let parent = source.find_commit(oid).unwrap();
+/// let feature = source.commit(None, &signature, &signature, "Feature", &tree, &[&parent]).unwrap();
+/// source.branch("zzz-feature", &source.find_commit(feature).unwrap(), false).unwrap();
+/// let repo = fetch_repo(&url, &tmp.join("clone"), &Credentials::default(), None, &mut |_| {}).unwrap();
+/// assert_eq!(repo.head().unwrap().target(), Some(oid));
+/// assert_eq!(repo.find_branch("zzz-feature", git2::BranchType::Local).unwrap().get().target(), Some(feature));
+///
+/// // Branches deleted on the remote are deleted too
+/// source.find_branch("zzz-feature", git2::BranchType::Local).unwrap().delete().unwrap();
+/// let repo = fetch_repo(&url, &tmp.join("clone"), &Credentials::default(), None, &mut |_| {}).unwrap();
+/// assert!(repo.find_branch("zzz-feature", git2::BranchType::Local).is_err());
+/// assert!(repo.find_branch("origin/zzz-feature", git2::BranchType::Remote).is_err());
+///
+/// // Repos over the size cap aren't downloaded
+/// let error = fetch_repo(&url, &tmp.join("large"), &Credentials::default(), Some(1), &mut |_| {}).err().unwrap();
+/// assert_eq!(error.error_type, actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
+/// assert!(!tmp.join("large").exists());
+///
+/// // Missing remotes are reported as errors
+/// assert!(fetch_repo("file:///does/not/exist", &tmp.join("missing"), &Credentials::default(), None, &mut |_| {}).is_err());
+/// ```
+pub fn fetch_repo(ssh_url: &str, out_dir: &Path, credentials: &Credentials, max_size: Option<u64>, transfer_progress: &mut dyn FnMut(&Progress)) -> Result<Repository, AppError> {
 
-    // Gets the home directory
-    /*
-    let home_env = match env::var("HOME") {
-        Ok(v) => v,
-        Err(_) => {
-            panic!("Can't find environment variable `$HOME` for ssh!");
-            // return Err(git2::Error::new(ErrorCode::Directory, ErrorClass::Os, "Can't find environment variable `$HOME` for ssh!".to_string()))
-        },
+    let fetch_error = |e: git2::Error, message: &str| AppError {
+        cause: Some(format!("{message} (repo: `{ssh_url}`, directory: `{out_dir:?}`) Error: {e:?}")),
+        message: Some(format!("{message} (repo: `{ssh_url}`)")),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
     };
-    */
-
-    // Sets Credential callback
-    let mut callbacks = RemoteCallbacks::new();
-    callbacks.transfer_progress(print_callback);
-    /*
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(username_from_url.unwrap(),
-            None,
-            Path::new(&home_env).join(ssh_key_path).as_path(),
-            None,
-        )
-    });
-    */
 
+    let is_new = !out_dir.is_dir();
     let repo = match out_dir.is_dir() {
-        true => Repository::open(out_dir).with_context(|| "Can't find the repo directory (do you have the right path?)")?,
-        false => {
+        true => {
+            let repo = Repository::open(out_dir).map_err(|e| fetch_error(e, "Can't find the repo directory"))?;
 
-            // Prepares fetch options
-            let mut fo = git2::FetchOptions::new();
-            fo.remote_callbacks(callbacks);
-            fo.depth(args.clone_depth as i32); // Sets max depth to the repo clone
+            // Clones made before credentials were stripped from urls could have them in `origin`
+            let origin_url = repo.find_remote("origin").ok().and_then(|v| v.url().map(|v| v.to_string()));
+            if origin_url.is_some_and(|v| v != ssh_url) {
+                repo.remote_set_url("origin", ssh_url).map_err(|e| fetch_error(e, "Can't configure repo"))?;
+            }
 
-            // Prepare builder.
-            let mut builder = git2::build::RepoBuilder::new();
-            builder.fetch_options(fo);
-            builder.bare(true);
+            repo
+        },
+        false => {
 
             debug!("Cloning for the first time...");
 
             let git_out_dir = out_dir.join(".git/");
             debug!("Cloning into: {git_out_dir:?}");
-            let _ = std::fs::create_dir_all(&git_out_dir).with_context(|| "Can't create directory!")?;
-            let repo = Repository::init_bare(git_out_dir).with_context(|| "Can't initialize repo")?;
-
-            // let repo = builder.clone(ssh_url, &git_out_dir).with_context(|| format!("Can't clone repo to `{out_dir:?}` (do you have the right URL?)"))?;
+            std::fs::create_dir_all(&git_out_dir).map_err(|e| AppError {
+                cause: Some(format!("Can't create directory: `{git_out_dir:?}` Error: {e:?}")),
+                message: Some("Can't create directory for repo!".to_string()),
+                error_type: StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+            let repo = Repository::init_bare(git_out_dir).map_err(|e| fetch_error(e, "Can't initialize repo"))?;
 
             // Sets some config rules
-            let mut config = repo.config()?;
+            let mut config = repo.config().map_err(|e| fetch_error(e, "Can't read repo config"))?;
 
             // Makes repo not bare
-            config.set_bool("core.bare", false)?;
+            config.set_bool("core.bare", false).map_err(|e| fetch_error(e, "Can't configure repo"))?;
 
             // Sets partial clone rules
             /*
@@ -87,74 +362,655 @@ pub fn fetch_repo(ssh_url: &str, out_dir: &Path, args: Arc<CliArgs>) -> Result<R
             };
 
             // Reports configuration
-            let mut entries = config.entries(None)?;
-
-            while let Some(entry) = entries.next() {
-                let res = entry.unwrap();
-                debug!("Config Entry: {} = {}", res.name().unwrap(), res.value().unwrap());
+            if let Ok(mut entries) = config.entries(None) {
+                while let Some(Ok(entry)) = entries.next() {
+                    debug!("Config Entry: {} = {}", entry.name().unwrap_or_default(), entry.value().unwrap_or_default());
+                }
             }
 
-            repo
+            // Reopens the repo so it isn't treated as bare anymore
+            drop(repo);
+            Repository::open(out_dir).map_err(|e| fetch_error(e, "Can't open the new repo"))?
         }
     };
 
-    // Updates the repo
-    debug!("Updating Index...");
-
-    let refs = repo.references_glob("refs/remotes/*/*").unwrap();
-    for refname in refs {
-        let oid = repo.refname_to_id(refname.unwrap().name().unwrap()).unwrap();
-        let object = repo.find_object(oid, None).unwrap();
-        repo.reset(&object, git2::ResetType::Hard, None).unwrap();
-    }
     debug!("Updating Refs...");
 
-    let remotes = repo.remotes()?;
+    let remotes = repo.remotes().map_err(|e| fetch_error(e, "Can't list remotes"))?;
     debug!("Found remotes: {:?}",
-        remotes.iter().map(|v| v.unwrap()).collect::<Vec<&str>>()
+        remotes.iter().flatten().collect::<Vec<&str>>()
     );
 
-    let branches = repo.branches(None)?
-        .map(|branch_res| {
-            let (branch, _branch_type) = branch_res.unwrap();
-            branch.name().unwrap().unwrap().to_string()
-        })
-        .collect::<Vec<String>>();
-    debug!("Found branches: {branches:?}");
+    // The branch the remote's `HEAD` points to (e.g. `refs/remotes/origin/main`)
+    let mut default_branch = None;
 
-    for remote_str in remotes.iter() {
-        let mut remote = repo.find_remote(remote_str.unwrap()).unwrap();
+    for remote_str in remotes.iter().flatten() {
+        let mut remote = repo.find_remote(remote_str).map_err(|e| fetch_error(e, "Can't find remote"))?;
 
-        let ref_specs_raw = remote.fetch_refspecs().unwrap();
-        let ref_specs = ref_specs_raw
-            .iter().map(|v| { v.unwrap() })
+        let ref_specs_raw = remote.fetch_refspecs().map_err(|e| fetch_error(e, "Can't read refspecs"))?;
+        let mut ref_specs = ref_specs_raw
+            .iter().flatten()
             .collect::<Vec<&str>>()
             ;
 
+        // Also fetches the start time overrides (see `get_start_overrides()`)
+        ref_specs.push(START_NOTES_REFSPEC);
+
+        // Sets Credential callback
+        let mut attempts = CredentialAttempts::default();
+        let mut too_large = false;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.transfer_progress(|v| {
+            debug!("Received Objects: #{:?}", v.received_objects());
+            transfer_progress(&v);
+
+            // Stops downloading instead of finding out the repo is too large once it's on disk
+            too_large = max_size.is_some_and(|max| v.received_bytes() as u64 > max);
+            !too_large
+        });
+        callbacks.credentials(|_url, username_from_url, allowed_types| {
+            credentials.next_credential(&mut attempts, username_from_url, allowed_types)
+        });
+
+        // Prunes the remote-tracking branches that were deleted on the remote, so they stop being
+        // mirrored and analyzed
         let mut fo = FetchOptions::new();
+        fo.remote_callbacks(callbacks);
+        fo.prune(FetchPrune::On);
         /*
         fo.custom_headers(&[r#""filter" SP blob:limit=15"#]); // Doesn't work!
         */
 
-        let _ = match remote.fetch(&ref_specs, Some(&mut fo), None) {
-            Ok(_) => (),
-            Err(v) => {
-                debug!(
-                    "Can't clone from remote: {:?} with refspecs: {:?}. Error: {v:?}",
-                    remote.name(),
-                    ref_specs
-                    );
-            },
+        debug!("Fetching Updates...");
+
+        let fetched = remote.fetch(&ref_specs, Some(&mut fo), None);
+        drop(fo);
+
+        if let Err(e) = fetched {
+
+            if too_large {
+                // Nothing of a cancelled first clone is worth keeping
+                if is_new {
+                    drop(remote);
+                    drop(repo);
+                    let _ = std::fs::remove_dir_all(out_dir);
+                }
+
+                return Err(AppError {
+                    cause: Some(format!("Cancelled fetching `{ssh_url}` after more than {max_size:?} bytes")),
+                    message: Some(format!("The repo is too large to analyze! (over {} MB)", max_size.unwrap_or_default() / MEGABYTE)),
+                    error_type: StatusCode::PAYLOAD_TOO_LARGE,
+                });
+            }
+
+            if is_auth_error(&e) {
+                return Err(AppError {
+                    cause: Some(format!("Failed to authenticate with remote: {:?} for url: `{ssh_url}`. Error: {e:?}", remote.name())),
+                    message: Some(format!("Authentication failed for `{ssh_url}`! (is an ssh key or access token for this host added to your account?)")),
+                    error_type: StatusCode::UNAUTHORIZED,
+                });
+            }
+
+            // The cached clone of a private repo can't be used by requests that can't fetch it
+            // (remotes report missing access as the repo not existing)
+            if repo.config().and_then(|v| v.get_bool(PRIVATE_CONFIG_KEY)).unwrap_or(false) {
+                return Err(AppError {
+                    cause: Some(format!("Can't fetch private repo from remote: {:?} for url: `{ssh_url}`. Error: {e:?}", remote.name())),
+                    message: Some(format!("Can't fetch `{ssh_url}`! (is an ssh key or access token for this host added to your account?)")),
+                    error_type: StatusCode::FORBIDDEN,
+                });
+            }
+
+            warn!(
+                "Can't fetch from remote: {:?} with refspecs: {:?}. Error: {e:?}",
+                remote.name(),
+                ref_specs
+                );
+        } else {
+            if attempts.used() {
+                // Repos that needed credentials stay marked as private
+                repo.config()
+                    .and_then(|mut v| v.set_bool(PRIVATE_CONFIG_KEY, true))
+                    .map_err(|e| fetch_error(e, "Can't configure repo"))?;
+            }
+
+            // The advertised refs are still available after the fetch disconnects
+            if default_branch.is_none() {
+                default_branch = remote.default_branch().ok()
+                    .and_then(|v| v.as_str().and_then(|v| v.strip_prefix("refs/heads/")).map(|v| format!("refs/remotes/{remote_str}/{v}")));
+            }
+        }
+
+    }
+
+    // Updates the repo
+    debug!("Updating Index...");
+
+    let remote_branches = repo.branches(Some(BranchType::Remote))
+        .map(|branches| branches
+            .flatten()
+            .filter_map(|(branch, _branch_type)| reference_tip(branch.get()).zip(branch.get().name().map(|v| v.to_string())))
+            .collect::<Vec<((String, Oid), String)>>()
+        )
+        .unwrap_or_default();
+    let found_refs = !remote_branches.is_empty();
+
+    // Falls back to the first remote branch if the remote didn't advertise its `HEAD`
+    let default_branch = default_branch
+        .filter(|v| remote_branches.iter().any(|(_tip, name)| name == v))
+        .or_else(|| remote_branches.first().map(|(_tip, name)| name.clone()));
+
+    // Mirrors the branches of the default remote as local branches so `HEAD` and the local
+    // branches mean the same thing as in a fresh clone
+    if let Some(default_branch) = &default_branch {
+        let remote_prefix = match default_branch.match_indices('/').nth(2) {
+            Some((i, _)) => &default_branch[..=i],
+            None => default_branch.as_str(),
         };
 
-        debug!("Fetching Updates...");
+        let mut mirrored = Vec::new();
+        for ((_shorthand, oid), name) in &remote_branches {
+            let Some(branch) = name.strip_prefix(remote_prefix) else {
+                continue;
+            };
 
+            let local_name = format!("refs/heads/{branch}");
+            if let Err(e) = repo.reference(&local_name, *oid, true, "git-stats: mirror remote branch") {
+                warn!("Can't update branch: `{local_name}`. Error: {e:?}");
+            }
+            mirrored.push(local_name);
+        }
+
+        let head = format!("refs/heads/{}", &default_branch[remote_prefix.len()..]);
+        let head_commit = repo.set_head(&head)
+            .and_then(|_| repo.head())
+            .and_then(|v| v.peel(ObjectType::Commit));
+        match head_commit {
+            Ok(commit) => {
+                if let Err(e) = repo.reset(&commit, git2::ResetType::Hard, None) {
+                    warn!("Can't reset to the default branch: `{head}`. Error: {e:?}");
+                }
+            },
+            Err(e) => warn!("Can't set HEAD to the default branch: `{head}`. Error: {e:?}"),
+        }
+
+        // Removes the branches that were deleted on the remote
+        if let Ok(branches) = repo.branches(Some(BranchType::Local)) {
+            for (mut branch, _branch_type) in branches.flatten() {
+                let stale = branch.get().name().is_some_and(|v| !mirrored.iter().any(|m| m == v));
+                if stale {
+                    debug!("Removing branch deleted on the remote: {:?}", branch.get().name());
+                    let _ = branch.delete();
+                }
+            }
+        }
+    }
+
+    if !found_refs && repo.head().is_err() {
+        return Err(AppError {
+            cause: Some(format!("No refs found after fetching: `{ssh_url}` into `{out_dir:?}`")),
+            message: Some(format!("Can't fetch repo from `{ssh_url}` (do you have the right URL?)")),
+            error_type: StatusCode::NOT_FOUND,
+        });
     }
 
+    let branches = repo.branches(None)
+        .map(|branches| branches
+            .flatten()
+            .filter_map(|(branch, _branch_type)| branch.name().ok().flatten().map(|v| v.to_string()))
+            .collect::<Vec<String>>()
+        )
+        .unwrap_or_default();
+    debug!("Found branches: {branches:?}");
+
     return Ok(repo);
 
 }
 
+/// Gets the name and commit id of a reference.
+/// Returns `None` for symbolic references (such as `origin/HEAD`) and references that don't point
+/// to a commit.
+fn reference_tip(reference: &Reference) -> Option<(String, Oid)> {
+
+    if reference.symbolic_target().is_some() {
+        return None;
+    }
+
+    let name = reference.shorthand()?.to_string();
+    let oid = reference.peel_to_commit().ok()?.id();
+
+    return Some((name, oid));
+}
+
+/// Resolves a `RefSelection` into a list of (reference name, commit id) pairs.
+/// Returns an error if nothing in the selection could be found.
+pub fn resolve_refs(repo: &Repository, selection: &RefSelection) -> Result<Vec<(String, Oid)>, AppError> {
+
+    let not_found = |cause: String| AppError {
+        cause: Some(cause),
+        message: Some(format!("Can't find any references matching: `{:?}`", selection)),
+        error_type: StatusCode::NOT_FOUND,
+    };
+
+    let mut tips = match selection {
+        RefSelection::Head => {
+            let head = repo.head().map_err(|e| not_found(format!("Can't resolve HEAD: `{}`", e.message())))?;
+            let oid = head.peel_to_commit().map_err(|e| not_found(format!("HEAD isn't a commit: `{}`", e.message())))?.id();
+            vec![(head.shorthand().unwrap_or("HEAD").to_string(), oid)]
+        },
+        RefSelection::All | RefSelection::Local | RefSelection::Remote => {
+            let branch_type = match selection {
+                RefSelection::Local => Some(BranchType::Local),
+                RefSelection::Remote => Some(BranchType::Remote),
+                _ => None,
+            };
+
+            repo.branches(branch_type)
+                .map_err(|e| not_found(format!("Can't list branches: `{}`", e.message())))?
+                .filter_map(|branch| branch.ok())
+                .filter_map(|(branch, _branch_type)| reference_tip(branch.get()))
+                .collect()
+        },
+        RefSelection::Glob(glob) => {
+            repo.references_glob(glob)
+                .map_err(|e| not_found(format!("Can't glob references with `{}`: `{}`", glob, e.message())))?
+                .filter_map(|reference| reference.ok())
+                .filter_map(|reference| reference_tip(&reference))
+                .collect()
+        },
+        RefSelection::Names(names) => {
+            let mut tips = Vec::with_capacity(names.len());
+            for name in names {
+                // Falls back to the remote tracking branch (e.g. `main` for `origin/main`)
+                let reference = repo.resolve_reference_from_short_name(name)
+                    .or_else(|e| repo.find_reference(&format!("refs/remotes/origin/{name}")).map_err(|_| e))
+                    .map_err(|e| not_found(format!("Can't find reference `{}`: `{}`", name, e.message())))?;
+                if let Some(tip) = reference_tip(&reference) {
+                    tips.push(tip);
+                }
+            }
+            tips
+        },
+    };
+
+    tips.sort();
+    tips.dedup();
+
+    if tips.is_empty() {
+        return Err(not_found("Reference selection resolved to nothing".to_string()));
+    }
+
+    return Ok(tips);
+}
+
+/// Creates a revwalk over every commit reachable from the given tips.
+/// Commits are yielded newest first and children are always yielded before their parents.
+pub fn revwalk_from_tips<'repo>(repo: &'repo Repository, tips: &[(String, Oid)]) -> Result<Revwalk<'repo>, git2::Error> {
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+
+    for (_name, oid) in tips {
+        revwalk.push(*oid)?;
+    }
+
+    return Ok(revwalk);
+}
+
+/// Tracks which tips the commits of a walk (see `revwalk_from_tips()`) are reachable from.
+/// Children are walked before their parents, so the tips of a commit are known once it's walked
+/// and only the parents that haven't been walked yet have to be kept.
+/// ```rust
+/// # use git_stats_web::git::{revwalk_from_tips, BranchTracker};
+/// # use git2::{Repository, Signature};
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let repo = Repository::init(dir.path()).unwrap();
+/// let signature = Signature::now("Alice", "alice@example.com").unwrap();
+/// # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+/// let base = repo.commit(None, &signature, &signature, "Base", &tree, &[]).unwrap();
+/// let parent = repo.find_commit(base).unwrap();
+/// let feature = repo.commit(None, &signature, &signature, "Feature", &tree, &[&parent]).unwrap();
+///
+/// let tips = vec![("main".to_string(), base), ("feature".to_string(), feature)];
+/// let mut tracker = BranchTracker::new(&tips);
+/// let branches = revwalk_from_tips(&repo, &tips).unwrap()
+///     .map(|oid| tracker.visit(&repo.find_commit(oid.unwrap()).unwrap()))
+///     .collect::<Vec<_>>();
+///
+/// assert_eq!(branches, vec![vec!["feature".to_string()], vec!["main".to_string(), "feature".to_string()]]);
+/// ```
+#[derive(Debug)]
+pub struct BranchTracker<'a> {
+    tips: &'a [(String, Oid)],
+    /// The indices of the tips every commit that hasn't been walked yet is reachable from.
+    pending: HashMap<Oid, Vec<usize>>,
+}
+
+impl<'a> BranchTracker<'a> {
+
+    /// Creates a tracker for a walk from `tips`.
+    pub fn new(tips: &'a [(String, Oid)]) -> Self {
+        let mut pending: HashMap<Oid, Vec<usize>> = HashMap::new();
+        for (i, (_name, oid)) in tips.iter().enumerate() {
+            pending.entry(*oid).or_default().push(i);
+        }
+
+        return Self { tips, pending };
+    }
+
+    /// Gets the names of the tips a commit is reachable from and passes them on to its parents.
+    /// Every commit of the walk has to be visited (in the order of the walk.)
+    pub fn visit(&mut self, commit: &Commit) -> Vec<String> {
+
+        let indices = self.pending.remove(&commit.id()).unwrap_or_default();
+
+        for parent_id in commit.parent_ids() {
+            let parent = self.pending.entry(parent_id).or_default();
+            parent.extend(&indices);
+            parent.sort_unstable();
+            parent.dedup();
+        }
+
+        return indices.iter().map(|i| self.tips[*i].0.clone()).collect();
+
+    }
+
+}
+
+/// Gets the manually set start times of commits from the notes under `START_NOTES_REF`.
+/// Notes set the start with a `start: <time>` line where the time is an RFC 3339 date (e.g.
+/// `2024-05-01T09:00:00+02:00`) or an epoch timestamp. Notes without a valid start are ignored.
+/// ```rust
+/// # use git_stats_web::git::{get_start_overrides, set_start_override};
+/// # use git2::{Repository, Signature};
+/// # use chrono::DateTime;
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let repo = Repository::init(dir.path()).unwrap();
+/// let signature = Signature::now("Alice", "alice@example.com").unwrap();
+/// # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+/// let oid = repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+///
+/// let start = DateTime::parse_from_rfc3339("2024-05-01T09:00:00+02:00").unwrap();
+/// set_start_override(&repo, oid, Some(start), &signature).unwrap();
+/// assert_eq!(get_start_overrides(&repo).get(&oid), Some(&start.timestamp()));
+///
+/// // Overrides can be removed again
+/// set_start_override(&repo, oid, None, &signature).unwrap();
+/// assert!(get_start_overrides(&repo).is_empty());
+/// ```
+pub fn get_start_overrides(repo: &Repository) -> HashMap<Oid, Timestamp> {
+
+    let notes = match repo.notes(Some(START_NOTES_REF)) {
+        Ok(v) => v,
+        // There are no overrides if the notes ref doesn't exist
+        Err(_) => return HashMap::new(),
+    };
+
+    return notes
+        .flatten()
+        .filter_map(|(_note_id, commit_id)| {
+            let note = repo.find_note(Some(START_NOTES_REF), commit_id).ok()?;
+            let start = note.message()?
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("start:"))
+                .and_then(|v| parse_time(v.trim()));
+
+            if start.is_none() {
+                debug!("Ignoring note without a valid start time on commit `{}`", commit_id);
+            }
+
+            Some((commit_id, start?))
+        })
+        .collect();
+
+}
+
+/// Sets (or removes, if `start` is `None`) the start time override of a commit.
+/// The note is written to `START_NOTES_REF` by `signature`.
+pub fn set_start_override(repo: &Repository, commit_id: Oid, start: Option<DateTime<FixedOffset>>, signature: &Signature) -> Result<(), git2::Error> {
+
+    let Some(start) = start else {
+        return match repo.note_delete(commit_id, Some(START_NOTES_REF), signature, signature) {
+            Err(e) if e.code() != ErrorCode::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    };
+
+    repo.note(signature, signature, Some(START_NOTES_REF), commit_id, &format!("start: {}\n", start.to_rfc3339()), true)?;
+    return Ok(());
+
+}
+
+/// Parses a time from an RFC 3339 date or an epoch timestamp.
+/// ```rust
+/// # use git_stats_web::git::parse_time;
+/// assert_eq!(parse_time("2024-05-01T09:00:00+02:00"), Some(1714546800));
+/// assert_eq!(parse_time("1714546800"), Some(1714546800));
+/// assert_eq!(parse_time("yesterday"), None);
+/// ```
+pub fn parse_time(value: &str) -> Option<Timestamp> {
+    return match DateTime::parse_from_rfc3339(value) {
+        Ok(v) => Some(v.timestamp()),
+        Err(_) => value.parse::<Timestamp>().ok(),
+    };
+}
+
+/// Pushes the start time overrides to every remote of a repo.
+/// If the push fails the notes are reset to `previous` (what `START_NOTES_REF` pointed to before
+/// they were changed) so the repo doesn't keep overrides its remotes don't have.
+pub fn push_start_overrides(repo: &Repository, credentials: &Credentials, previous: Option<Oid>) -> Result<(), AppError> {
+
+    let Err(e) = push_notes(repo, credentials) else {
+        return Ok(());
+    };
+
+    warn!("Failed to push `{}`, resetting the notes. Error: {:?}", START_NOTES_REF, e.cause);
+
+    let reset = match previous {
+        Some(oid) => repo.reference(START_NOTES_REF, oid, true, "git-stats: reset notes after failed push").map(|_| ()),
+        None => repo.find_reference(START_NOTES_REF).and_then(|mut v| v.delete()),
+    };
+
+    if let Err(reset_error) = reset {
+        warn!("Can't reset `{}` after a failed push. Error: {:?}", START_NOTES_REF, reset_error);
+    }
+
+    return Err(e);
+
+}
+
+/// Pushes `START_NOTES_REF` to every remote of a repo.
+fn push_notes(repo: &Repository, credentials: &Credentials) -> Result<(), AppError> {
+
+    let push_error = |cause: String| AppError {
+        cause: Some(cause),
+        message: Some("Can't push the start time to the repo! (does your account have write access?)".to_string()),
+        error_type: StatusCode::BAD_GATEWAY,
+    };
+
+    let remotes = repo.remotes().map_err(|e| push_error(format!("Can't list remotes. Error: {e:?}")))?;
+
+    for remote_str in remotes.iter().flatten() {
+        let mut remote = repo.find_remote(remote_str).map_err(|e| push_error(format!("Can't find remote: `{remote_str}`. Error: {e:?}")))?;
+
+        let mut attempts = CredentialAttempts::default();
+        let mut rejection = None;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|_url, username_from_url, allowed_types| {
+            credentials.next_credential(&mut attempts, username_from_url, allowed_types)
+        });
+        callbacks.push_update_reference(|reference, status| {
+            if let Some(status) = status {
+                rejection = Some(format!("`{reference}` was rejected: {status}"));
+            }
+            Ok(())
+        });
+
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        remote.push(&[START_NOTES_REFSPEC], Some(&mut push_options))
+            .map_err(|e| push_error(format!("Can't push notes to remote: `{remote_str}`. Error: {e:?}")))?;
+
+        drop(push_options);
+        if let Some(rejection) = rejection {
+            return Err(push_error(format!("Can't push notes to remote: `{remote_str}`. {rejection}")));
+        }
+    }
+
+    return Ok(());
+
+}
+
+/// Gets the tags (annotated and lightweight) pointing to any of the `commits` (newest first.)
+/// ```rust
+/// # use git_stats_web::git::get_milestones;
+/// # use git2::{Repository, Signature, Time};
+/// # use std::collections::HashSet;
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let repo = Repository::init(dir.path()).unwrap();
+/// let signature = Signature::new("Alice", "alice@example.com", &Time::new(1000, 0)).unwrap();
+/// # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+/// let oid = repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+/// let commit = repo.find_object(oid, None).unwrap();
+///
+/// let tagger = Signature::new("Alice", "alice@example.com", &Time::new(2000, 0)).unwrap();
+/// repo.tag("v1.0", &commit, &tagger, "First release", false).unwrap();
+/// repo.tag_lightweight("nightly", &commit, false).unwrap();
+///
+/// let milestones = get_milestones(&repo, &HashSet::from([oid])).unwrap();
+/// assert_eq!(milestones.iter().map(|v| (v.tag.as_str(), v.date)).collect::<Vec<_>>(), vec![("v1.0", 2000), ("nightly", 1000)]);
+/// assert_eq!(milestones[0].message.as_deref(), Some("First release"));
+/// assert_eq!(milestones[0].target, oid.to_string());
+///
+/// // Tags of commits that weren't analyzed are left out
+/// assert!(get_milestones(&repo, &HashSet::new()).unwrap().is_empty());
+/// ```
+pub fn get_milestones(repo: &Repository, commits: &HashSet<Oid>) -> Result<Vec<Milestone>, git2::Error> {
+
+    let mut milestones = Vec::new();
+
+    for reference in repo.references_glob("refs/tags/*")?.flatten() {
+
+        // Tags can point to things other than commits
+        let Ok(commit) = reference.peel_to_commit() else {
+            continue;
+        };
+
+        if !commits.contains(&commit.id()) {
+            continue;
+        }
+
+        // Lightweight tags can't be peeled to a tag object
+        let tag = reference.peel_to_tag().ok();
+
+        milestones.push(Milestone {
+            tag: reference.shorthand().unwrap_or_default().to_string(),
+            target: commit.id().to_string(),
+            date: tag.as_ref()
+                .and_then(|v| v.tagger())
+                .map(|v| v.when().seconds())
+                .unwrap_or(commit.time().seconds()),
+            message: tag.as_ref()
+                .and_then(|v| v.message())
+                .map(|v| v.trim().to_string()),
+            repo: String::new(),
+        });
+
+    }
+
+    milestones.sort_by_key(|v| Reverse(v.date));
+    return Ok(milestones);
+
+}
+
+/// An alias mapping one identity onto a canonical identity (like an entry in a `.mailmap` file.)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityAlias {
+    /// The name to use instead of the alias's name. The name is kept if unset.
+    pub canonical_name: Option<String>,
+    /// The email to use instead of the alias's email. The email is kept if unset.
+    pub canonical_email: Option<String>,
+    /// The name of the alias. Only commits with this name are mapped if set.
+    pub alias_name: Option<String>,
+    /// The email of the alias.
+    pub alias_email: String,
+}
+
+/// Gets the mailmap of a repo with additional aliases added to it.
+/// The aliases take priority over entries of the repo's `.mailmap`.
+/// ```rust
+/// # use git_stats_web::git::{get_mailmap, IdentityAlias};
+/// # use git2::{Repository, Signature};
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let repo = Repository::init(dir.path()).unwrap();
+/// let mailmap = get_mailmap(&repo, &[IdentityAlias {
+///     canonical_name: Some("Alice".to_string()),
+///     canonical_email: Some("alice@work.com".to_string()),
+///     alias_name: None,
+///     alias_email: "alice@laptop.local".to_string(),
+/// }]).unwrap();
+///
+/// let signature = Signature::now("alice", "alice@laptop.local").unwrap();
+/// let resolved = mailmap.resolve_signature(&signature).unwrap();
+/// assert_eq!(resolved.name(), Some("Alice"));
+/// assert_eq!(resolved.email(), Some("alice@work.com"));
+/// ```
+pub fn get_mailmap(repo: &Repository, aliases: &[IdentityAlias]) -> Result<Mailmap, git2::Error> {
+
+    let mut mailmap = match repo.mailmap() {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Can't read mailmap from repo, using an empty one. Error: {e:?}");
+            Mailmap::new()?
+        },
+    };
+
+    for alias in aliases {
+        mailmap.add_entry(
+            alias.canonical_name.as_deref(),
+            alias.canonical_email.as_deref(),
+            alias.alias_name.as_deref(),
+            &alias.alias_email,
+        )?;
+    }
+
+    return Ok(mailmap);
+}
+
+/// Gets the co-authors listed in the `Co-authored-by:` trailers of a commit message.
+/// Identities are canonicalized with `mailmap` and trailers that aren't formatted as
+/// `Name <email>` are ignored.
+/// ```rust
+/// # use git_stats_web::git::get_co_authors;
+/// # use git2::Mailmap;
+/// let message = "Pair on the parser\n\nCo-authored-by: Bob <bob@example.com>\nco-authored-by: not an identity\n";
+/// let co_authors = get_co_authors(message, &Mailmap::new().unwrap());
+/// assert_eq!(co_authors.len(), 1);
+/// assert_eq!(co_authors[0].to_string(), "Bob <bob@example.com>");
+/// ```
+pub fn get_co_authors(message: &str, mailmap: &Mailmap) -> Vec<Signature<'static>> {
+
+    let trailers = match message_trailers_strs(message) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Can't parse the trailers of commit message: `{message}`. Error: {e:?}");
+            return Vec::new();
+        },
+    };
+
+    return trailers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("Co-authored-by"))
+        .filter_map(|(_, value)| {
+            let (name, email) = value.trim().strip_suffix('>')?.split_once('<')?;
+            let signature = Signature::now(name.trim(), email.trim()).ok()?;
+            return mailmap.resolve_signature(&signature).ok();
+        })
+        .collect();
+
+}
+
 /// Gets the head commit from a repo
 pub fn get_head_commit(repo: &Repository) -> Commit {
 
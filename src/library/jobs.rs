@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use git2::Progress;
+use log::debug;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+use super::{calendar::Report, cli::CliArgs, errors::AppError};
+
+/// The progress of a background job.
+/// Gets sent to the browser (as JSON) while the job runs.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for other jobs to finish.
+    Queued,
+    /// Downloading objects from a repo's remote.
+    Fetching {
+        /// The url of the repo.
+        repo: String,
+        /// The amount of objects downloaded so far.
+        received_objects: usize,
+        /// The amount of objects the remote is sending.
+        total_objects: usize,
+        /// The amount of bytes downloaded so far.
+        received_bytes: usize,
+    },
+    /// Reading (and diffing) a repo's commits.
+    Analyzing {
+        /// The url of the repo.
+        repo: String,
+        /// The amount of commits read so far.
+        commits: usize,
+        /// The most commits that get read.
+        max_commits: Option<usize>,
+    },
+    /// The result is ready.
+    Done,
+    /// The job failed, the message can be shown to the user.
+    Failed {
+        /// The error message.
+        message: String,
+    },
+}
+
+impl JobStatus {
+
+    /// Returns true if the job won't be updated anymore.
+    pub fn is_finished(&self) -> bool {
+        return matches!(self, Self::Done | Self::Failed { .. });
+    }
+
+}
+
+/// A clone and/or analysis that runs in the background.
+/// The status can be watched (e.g. by a WebSocket) and the result is kept once it finishes.
+#[derive(Debug)]
+pub struct Job {
+    status: watch::Sender<JobStatus>,
+    result: Mutex<Option<Result<Report, AppError>>>,
+    finished_at: Mutex<Option<Instant>>,
+}
+
+impl Job {
+
+    /// Creates a queued job that isn't tracked by a queue (e.g. for requests that wait for their
+    /// result.)
+    pub fn detached() -> Self {
+        return Self {
+            status: watch::Sender::new(JobStatus::Queued),
+            result: Mutex::new(None),
+            finished_at: Mutex::new(None),
+        };
+    }
+
+    /// Gets the current status.
+    pub fn status(&self) -> JobStatus {
+        return self.status.borrow().clone();
+    }
+
+    /// Subscribes to the status updates of the job.
+    pub fn subscribe(&self) -> watch::Receiver<JobStatus> {
+        return self.status.subscribe();
+    }
+
+    /// Updates the status.
+    pub fn set_status(&self, status: JobStatus) {
+        self.status.send_replace(status);
+    }
+
+    /// Updates the status with the object transfer progress of a fetch.
+    pub fn fetch_progress(&self, repo: &str, progress: &Progress) {
+        self.set_status(JobStatus::Fetching {
+            repo: repo.to_string(),
+            received_objects: progress.received_objects(),
+            total_objects: progress.total_objects(),
+            received_bytes: progress.received_bytes(),
+        });
+    }
+
+    /// Updates the status with the amount of commits that were analyzed.
+    pub fn analysis_progress(&self, repo: &str, commits: usize, max_commits: Option<usize>) {
+        self.set_status(JobStatus::Analyzing {
+            repo: repo.to_string(),
+            commits,
+            max_commits,
+        });
+    }
+
+    /// Stores the result and marks the job as finished.
+    pub fn finish(&self, result: Result<Report, AppError>) {
+
+        let status = match &result {
+            Ok(_) => JobStatus::Done,
+            Err(e) => JobStatus::Failed {
+                message: e.message.clone().unwrap_or("NO ERROR MESSAGE PROVIDED!".to_string()),
+            },
+        };
+
+        *lock(&self.result) = Some(result);
+        *lock(&self.finished_at) = Some(Instant::now());
+        self.set_status(status);
+
+    }
+
+    /// Gets the result, `None` if the job is still running.
+    pub fn result(&self) -> Option<Result<Report, AppError>> {
+        return lock(&self.result).clone();
+    }
+
+}
+
+/// The background jobs of the server, keyed by a random id.
+/// Only `max_running` jobs run at a time and finished jobs are forgotten after `retention`.
+/// Anyone with a job's id can read its result so the ids shouldn't be shared.
+#[derive(Debug)]
+pub struct JobQueue {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    permits: Arc<Semaphore>,
+    retention: Duration,
+}
+
+impl JobQueue {
+
+    /// Creates an empty queue.
+    pub fn new(max_running: usize, retention: Duration) -> Self {
+        return Self {
+            jobs: Mutex::new(HashMap::new()),
+            permits: Arc::new(Semaphore::new(max_running.max(1))),
+            retention,
+        };
+    }
+
+    /// Creates the queue from the CLI arguments.
+    pub fn from_args(args: &CliArgs) -> Self {
+        return Self::new(args.max_jobs, Duration::from_secs(args.job_retention_seconds));
+    }
+
+    /// Adds a new (queued) job and returns it with its id.
+    /// ```rust
+    /// # use git_stats_web::jobs::{JobQueue, JobStatus};
+    /// # use std::time::Duration;
+    /// let queue = JobQueue::new(1, Duration::from_secs(60));
+    ///
+    /// let (id, job) = queue.create();
+    /// assert_eq!(queue.get(&id).unwrap().status(), JobStatus::Queued);
+    /// assert!(job.result().is_none());
+    ///
+    /// job.finish(Ok(Default::default()));
+    /// assert_eq!(queue.get(&id).unwrap().status(), JobStatus::Done);
+    /// assert!(job.result().unwrap().is_ok());
+    ///
+    /// assert!(queue.get("missing").is_none());
+    /// ```
+    pub fn create(&self) -> (String, Arc<Job>) {
+
+        let mut jobs = lock(&self.jobs);
+
+        // Forgets jobs that finished a while ago
+        jobs.retain(|id, job| {
+            let expired = lock(&job.finished_at).is_some_and(|v| v.elapsed() > self.retention);
+            if expired {
+                debug!("Removing finished job: `{id}`");
+            }
+            !expired
+        });
+
+        let id = format!("{:016x}{:016x}", thread_rng().gen::<u64>(), thread_rng().gen::<u64>());
+        let job = Arc::new(Job::detached());
+        jobs.insert(id.clone(), job.clone());
+
+        return (id, job);
+
+    }
+
+    /// Gets a job by its id.
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        return lock(&self.jobs).get(id).cloned();
+    }
+
+    /// Waits until fewer than the maximum amount of jobs are running.
+    /// The job can run until the permit is dropped.
+    pub async fn permit(&self) -> OwnedSemaphorePermit {
+        // The semaphore is never closed
+        return self.permits.clone().acquire_owned().await.unwrap();
+    }
+
+}
+
+/// Locks a mutex, ignoring poisoning (the data is still usable.)
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    return match mutex.lock() {
+        Ok(v) => v,
+        Err(e) => e.into_inner(),
+    };
+}
@@ -0,0 +1,280 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::http::StatusCode;
+use git2::{ErrorCode, Repository, RepositoryOpenFlags};
+use log::{debug, warn};
+use serde::Serialize;
+use url::Url;
+
+use super::{cli::CliArgs, errors::AppError};
+
+/// Directories that never contain repos worth analyzing.
+const SKIPPED_DIRECTORIES: &[&str] = &["node_modules", "target", "vendor"];
+
+/// A repository found under one of the local roots.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LocalRepo {
+    /// The path of the repo relative to its root.
+    pub name: String,
+    /// The `file://` url used for analyzing the repo.
+    pub url: String,
+}
+
+/// The directories the server is allowed to open local repositories from.
+/// Scanning the roots for repos is slow so the result is cached.
+#[derive(Debug)]
+pub struct LocalRoots {
+    roots: Vec<PathBuf>,
+    /// Directories inside the roots that are never opened (the clone cache.)
+    excluded: Vec<PathBuf>,
+    max_depth: usize,
+    cache_duration: Duration,
+    scan: Mutex<Option<(Instant, Vec<LocalRepo>)>>,
+}
+
+impl LocalRoots {
+
+    /// Creates the allowlist of roots from the CLI arguments.
+    /// Roots that don't exist are left out and local repos are never allowed without
+    /// `--allow-local` and at least one `--local-root`.
+    /// The tmp directory is excluded, so cached clones (of private repos) can't be opened as
+    /// local repos.
+    pub fn from_args(args: &CliArgs) -> Self {
+
+        if args.allow_local && args.local_root.is_empty() {
+            warn!("Local repos are disabled since no `--local-root` is set!");
+        }
+
+        let roots = match args.allow_local {
+            true => args.local_root
+                .iter()
+                .filter_map(|root| match fs::canonicalize(root) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        warn!("Can't use local root: `{}` (error: {})", root, e);
+                        None
+                    },
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        return Self::new(roots, vec![PathBuf::from(args.get_tmp_path())], args.scan_depth, Duration::from_secs(args.scan_cache_seconds));
+
+    }
+
+    /// Creates the allowlist from already canonicalized roots.
+    /// Nothing inside the `excluded` directories is listed or opened.
+    pub fn new(roots: Vec<PathBuf>, excluded: Vec<PathBuf>, max_depth: usize, cache_duration: Duration) -> Self {
+        return Self {
+            roots,
+            excluded,
+            max_depth,
+            cache_duration,
+            scan: Mutex::new(None),
+        };
+    }
+
+    /// Returns true if no roots are configured.
+    pub fn is_empty(&self) -> bool {
+        return self.roots.is_empty();
+    }
+
+    /// Checks if a (canonicalized) path is inside one of the roots and not an excluded directory.
+    pub fn contains(&self, path: &Path) -> bool {
+        return self.roots.iter().any(|root| path.starts_with(root)) && !self.is_excluded(path);
+    }
+
+    /// Checks if a (canonicalized) path is inside one of the excluded directories.
+    /// These are resolved on every check since they might not exist yet when the server starts.
+    fn is_excluded(&self, path: &Path) -> bool {
+        return self.excluded
+            .iter()
+            .any(|excluded| path.starts_with(fs::canonicalize(excluded).unwrap_or(excluded.clone())));
+    }
+
+    /// Opens the repo at a `file://` url.
+    /// The repo is discovered from the path (so it can be a subdirectory of a repo, a worktree or
+    /// a bare repo) and has to be inside one of the roots after resolving symlinks and `..`.
+    /// Returns a 404 error if there is no repo at the path and a 403 error if it's outside the
+    /// roots (or excluded.)
+    /// ```rust
+    /// # use git_stats_web::local::LocalRoots;
+    /// # use actix_web::http::StatusCode;
+    /// # use std::time::Duration;
+    /// # use url::Url;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let tmp = dir.path().canonicalize().unwrap();
+    /// git2::Repository::init(tmp.join("code/project")).unwrap();
+    /// std::fs::create_dir_all(tmp.join("code/project/src")).unwrap();
+    /// git2::Repository::init(tmp.join("private")).unwrap();
+    /// git2::Repository::init(tmp.join("code/cache/clone")).unwrap();
+    ///
+    /// let roots = LocalRoots::new(vec![tmp.join("code")], vec![tmp.join("code/cache")], 3, Duration::from_secs(60));
+    /// let url = |path: &str| Url::from_file_path(tmp.join(path)).unwrap();
+    ///
+    /// assert!(roots.open_repo(&url("code/project/src")).is_ok());
+    /// assert_eq!(roots.open_repo(&url("code/missing")).err().unwrap().error_type, StatusCode::NOT_FOUND);
+    /// assert_eq!(roots.open_repo(&url("code/../private")).err().unwrap().error_type, StatusCode::FORBIDDEN);
+    /// assert_eq!(roots.open_repo(&url("code/cache/clone")).err().unwrap().error_type, StatusCode::FORBIDDEN);
+    /// ```
+    pub fn open_repo(&self, url: &Url) -> Result<Repository, AppError> {
+
+        let error = |cause: String, message: &str, error_type: StatusCode| AppError {
+            cause: Some(cause),
+            message: Some(message.to_string()),
+            error_type,
+        };
+
+        let forbidden = |path: &Path| error(
+            format!("Local path `{}` from url `{}` is outside of the local roots: {:?}", path.display(), url, self.roots),
+            "Local repos are only allowed in the configured local roots!",
+            StatusCode::FORBIDDEN,
+        );
+
+        let path = url.to_file_path().map_err(|_| error(
+            format!("Can't get a local path from url: `{}`", url),
+            "Invalid `file://` url!",
+            StatusCode::BAD_REQUEST,
+        ))?;
+
+        let path = fs::canonicalize(&path).map_err(|e| error(
+            format!("Can't find local path `{}` (error: {})", path.display(), e),
+            "Local repo not found!",
+            StatusCode::NOT_FOUND,
+        ))?;
+
+        if !self.contains(&path) {
+            return Err(forbidden(&path));
+        }
+
+        let repo = Repository::open_ext(&path, RepositoryOpenFlags::empty(), &[] as &[&Path]).map_err(|e| error(
+            format!("Can't open repo at local path `{}` (error: {})", path.display(), e.message()),
+            "Local repo not found!",
+            match e.code() {
+                ErrorCode::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+        ))?;
+
+        // Discovery can walk up out of the roots so the repo itself has to be checked too
+        let repo_path = repo.workdir().unwrap_or(repo.path());
+        let repo_path = fs::canonicalize(repo_path).unwrap_or(repo_path.to_path_buf());
+        if !self.contains(&repo_path) {
+            return Err(forbidden(&repo_path));
+        }
+
+        debug!("Opened local repo at `{}`", repo_path.display());
+        return Ok(repo);
+
+    }
+
+    /// Gets every repo under the roots.
+    /// The roots are only scanned again once the cached scan expires or if `refresh` is set.
+    /// ```rust
+    /// # use git_stats_web::local::LocalRoots;
+    /// # use std::time::Duration;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let tmp = dir.path();
+    /// git2::Repository::init(tmp.join("code/project")).unwrap();
+    /// std::fs::create_dir_all(tmp.join("code/notes")).unwrap();
+    /// git2::Repository::init(tmp.join("cache/clone")).unwrap();
+    ///
+    /// let roots = LocalRoots::new(vec![tmp.canonicalize().unwrap()], vec![tmp.join("cache")], 3, Duration::from_secs(60));
+    /// let repos = roots.repos(false);
+    /// assert_eq!(repos.len(), 1);
+    /// assert_eq!(repos[0].name, "code/project");
+    /// ```
+    pub fn repos(&self, refresh: bool) -> Vec<LocalRepo> {
+
+        let mut scan = match self.scan.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        if let Some((scanned_at, repos)) = scan.as_ref() {
+            if !refresh && scanned_at.elapsed() < self.cache_duration {
+                return repos.clone();
+            }
+        }
+
+        let mut repos = Vec::new();
+        for root in &self.roots {
+            scan_directory(root, root, self.max_depth, &|path| self.is_excluded(path), &mut repos);
+        }
+
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
+        debug!("Found {} repos under local roots: {:?}", repos.len(), self.roots);
+
+        *scan = Some((Instant::now(), repos.clone()));
+        return repos;
+
+    }
+
+}
+
+/// Checks if a directory is a git repository (either with a `.git` directory/file or bare.)
+fn is_repo(path: &Path) -> bool {
+    return path.join(".git").exists()
+        || (path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir());
+}
+
+/// Recursively looks for repos in a directory.
+/// Hidden directories, symlinks and `excluded` directories are skipped and repos aren't searched
+/// for nested repos.
+fn scan_directory(root: &Path, path: &Path, depth: usize, excluded: &dyn Fn(&Path) -> bool, repos: &mut Vec<LocalRepo>) {
+
+    if excluded(path) {
+        return;
+    }
+
+    if is_repo(path) {
+        let name = path.strip_prefix(root).unwrap_or(path).display().to_string();
+
+        if let Ok(url) = Url::from_directory_path(path) {
+            repos.push(LocalRepo {
+                name: match name.is_empty() {
+                    true => path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                    false => name,
+                },
+                url: url.as_str().trim_end_matches('/').to_string(),
+            });
+        }
+
+        return;
+    }
+
+    if depth == 0 {
+        return;
+    }
+
+    let entries = match fs::read_dir(path) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Can't scan directory: `{}` (error: {})", path.display(), e);
+            return;
+        },
+    };
+
+    for entry in entries.flatten() {
+
+        // `DirEntry::file_type()` doesn't follow symlinks
+        if !entry.file_type().is_ok_and(|v| v.is_dir()) {
+            continue;
+        }
+
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with('.') || SKIPPED_DIRECTORIES.contains(&file_name.as_str()) {
+            continue;
+        }
+
+        scan_directory(root, &entry.path(), depth - 1, excluded, repos);
+
+    }
+
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::http::StatusCode;
+use log::{debug, warn};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use super::errors::AppError;
+
+/// How long to wait before checking a lock file held by another process again.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Locks for the repos in the tmp directory so only one request clones, fetches or reads a repo
+/// at a time.
+/// Requests in the same process wait on an in-process mutex and other processes (e.g. several
+/// instances sharing a tmp directory) are kept out by a lock file next to the repo.
+#[derive(Debug)]
+pub struct RepoLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
+    stale_after: Duration,
+}
+
+/// A held lock on a repo. The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct RepoLock {
+    _guard: OwnedMutexGuard<()>,
+    lock_file: PathBuf,
+    /// Stops refreshing the lock file once dropped (see `RepoLocks::keep_fresh()`.)
+    _refresh: Sender<()>,
+}
+
+impl RepoLocks {
+
+    /// Creates an empty set of locks.
+    /// Lock files that weren't refreshed for `stale_after` are assumed to be left over from a
+    /// crashed process and get taken over.
+    pub fn new(stale_after: Duration) -> Self {
+        return Self {
+            locks: Mutex::new(HashMap::new()),
+            stale_after,
+        };
+    }
+
+    /// Gets the in-process mutex of a repo.
+    fn mutex(&self, path: &Path) -> Arc<AsyncMutex<()>> {
+        let mut locks = match self.locks.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        return locks.entry(path.to_path_buf()).or_default().clone();
+    }
+
+    /// Locks a repo, waiting until every other request (in any process) is done with it.
+    /// ```rust
+    /// # use git_stats_web::lock::RepoLocks;
+    /// # use std::time::Duration;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let tmp = dir.path();
+    /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+    /// let locks = RepoLocks::new(Duration::from_secs(60));
+    ///
+    /// let lock = locks.lock(&tmp.join("repo")).await.unwrap();
+    /// assert!(locks.try_lock(&tmp.join("repo")).is_none()); // already locked
+    /// assert!(locks.try_lock(&tmp.join("other")).is_some());
+    ///
+    /// drop(lock);
+    /// assert!(locks.try_lock(&tmp.join("repo")).is_some());
+    /// # });
+    /// ```
+    pub async fn lock(&self, path: &Path) -> Result<RepoLock, AppError> {
+
+        let guard = self.mutex(path).lock_owned().await;
+
+        loop {
+            if let Some(lock_file) = self.create_lock_file(path)? {
+                let refresh = self.keep_fresh(&lock_file);
+                return Ok(RepoLock { _guard: guard, lock_file, _refresh: refresh });
+            }
+
+            debug!("Waiting for another process to release repo: `{}`", path.display());
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        }
+
+    }
+
+    /// Locks a repo if nothing else is using it.
+    pub fn try_lock(&self, path: &Path) -> Option<RepoLock> {
+
+        let guard = self.mutex(path).try_lock_owned().ok()?;
+        let lock_file = self.create_lock_file(path).ok()??;
+        let refresh = self.keep_fresh(&lock_file);
+
+        return Some(RepoLock { _guard: guard, lock_file, _refresh: refresh });
+
+    }
+
+    /// Keeps updating the modification time of a held lock file so other processes don't take
+    /// it over while a long clone or analysis is still running.
+    /// Stops once the returned sender is dropped.
+    fn keep_fresh(&self, lock_file: &Path) -> Sender<()> {
+
+        let (sender, receiver) = mpsc::channel::<()>();
+        let lock_file = lock_file.to_path_buf();
+        let interval = (self.stale_after / 3).max(LOCK_POLL_INTERVAL);
+
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                // The file isn't created again if it was removed in the meantime
+                let refreshed = OpenOptions::new()
+                    .write(true)
+                    .open(&lock_file)
+                    .and_then(|v| v.set_modified(SystemTime::now()));
+
+                if let Err(e) = refreshed {
+                    warn!("Failed to refresh lock file: `{}` Error: {:?}", lock_file.display(), e);
+                }
+            }
+        });
+
+        return sender;
+
+    }
+
+    /// Creates the lock file of a repo.
+    /// Returns `None` if another process holds the lock.
+    fn create_lock_file(&self, path: &Path) -> Result<Option<PathBuf>, AppError> {
+
+        let lock_file = PathBuf::from(format!("{}.lock", path.display()));
+
+        let lock_error = |e: std::io::Error| AppError {
+            cause: Some(format!("Can't create lock file: `{}` Error: {:?}", lock_file.display(), e)),
+            message: Some("Can't lock the repo directory!".to_string()),
+            error_type: StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        if let Some(parent) = lock_file.parent() {
+            fs::create_dir_all(parent).map_err(lock_error)?;
+        }
+
+        return match OpenOptions::new().write(true).create_new(true).open(&lock_file) {
+            Ok(mut file) => {
+                let _ = write!(file, "{}", std::process::id());
+                Ok(Some(lock_file))
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let age = fs::metadata(&lock_file)
+                    .and_then(|v| v.modified())
+                    .ok()
+                    .and_then(|v| SystemTime::now().duration_since(v).ok());
+
+                if age.is_some_and(|age| age > self.stale_after) {
+                    warn!("Removing stale lock file: `{}`", lock_file.display());
+                    let _ = fs::remove_file(&lock_file);
+                }
+
+                Ok(None)
+            },
+            Err(e) => Err(lock_error(e)),
+        };
+
+    }
+
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.lock_file) {
+            warn!("Failed to remove lock file: `{}` Error: {:?}", self.lock_file.display(), e);
+        }
+    }
+}
@@ -16,6 +16,30 @@ pub mod git;
 /// A module for application database connections.
 pub mod database;
 
+/// A module for per-commit diff statistics.
+pub mod stats;
+
+/// A module for verifying commit signatures.
+pub mod signature;
+
+/// A module for encrypting the secrets stored in the database.
+pub mod secrets;
+
+/// A module for filtering which commits get reported.
+pub mod filter;
+
+/// A module for managing the repos cloned into the tmp directory.
+pub mod cache;
+
+/// A module for locking the repos in the tmp directory.
+pub mod lock;
+
+/// A module for running clones and analyses as background jobs.
+pub mod jobs;
+
+/// A module for finding repositories in the local roots the server is allowed to open.
+pub mod local;
+
 /// A module for misc utilities.
 pub mod utils;
 
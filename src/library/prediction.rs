@@ -1,6 +1,79 @@
-use std::collections::hash_map::HashMap;
+use std::{cell::OnceCell, collections::hash_map::HashMap, fmt::Debug, str::FromStr};
 use super::aliases::Timestamp;
 
+/// The attributes the regression is fitted on (in the order of its coefficients, after the
+/// intercept.)
+const REGRESSION_ATTRIBUTES: [PredictionAttributes; 4] = [
+    PredictionAttributes::FilesChanged,
+    PredictionAttributes::LinesAdded,
+    PredictionAttributes::LinesRemoved,
+    PredictionAttributes::FilesRenamed,
+];
+
+/// The amount of coefficients of the regression (the intercept and one per attribute.)
+const REGRESSION_COEFFICIENTS: usize = REGRESSION_ATTRIBUTES.len() + 1;
+
+/// The regression needs this many samples per coefficient, with fewer samples the ratio model is
+/// used.
+const MIN_SAMPLES_PER_COEFFICIENT: usize = 5;
+
+/// A model that estimates how long a commit took from its diff attributes.
+/// The estimate is used as the start of the first commit of a session (since there's no previous
+/// commit to measure it from.)
+/// Estimators are trained on the commits made within a session, where the time since the
+/// previous commit is known.
+pub trait StartTimeEstimator: Debug {
+    /// Adds a commit made within a session, `time` is how long it took.
+    fn insert(&mut self, values: &[(PredictionAttributes, i32)], time: Timestamp);
+
+    /// Estimates how long a commit took.
+    fn predict(&self, values: &[(PredictionAttributes, i32)]) -> Timestamp;
+}
+
+/// The estimators that can be selected for a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EstimatorKind {
+    /// The average of the per-attribute ratios of time to value (see `PredictionStructure`.)
+    #[default]
+    Ratio,
+    /// A linear regression over every attribute (see `RegressionEstimator`.)
+    Regression,
+    /// A quantile (e.g. `0.5` for the median) of the time per line changed (see
+    /// `QuantileEstimator`.)
+    Quantile(f64),
+}
+
+impl EstimatorKind {
+    /// Creates an untrained estimator of this kind.
+    pub fn create(&self) -> Box<dyn StartTimeEstimator> {
+        return match self {
+            Self::Ratio => Box::new(PredictionStructure::new()),
+            Self::Regression => Box::new(RegressionEstimator::new()),
+            Self::Quantile(quantile) => Box::new(QuantileEstimator::new(*quantile)),
+        };
+    }
+}
+
+impl FromStr for EstimatorKind {
+    type Err = String;
+
+    /// ```rust
+    /// # use git_stats_web::prediction::EstimatorKind;
+    /// assert_eq!("ratio".parse(), Ok(EstimatorKind::Ratio));
+    /// assert_eq!("regression".parse(), Ok(EstimatorKind::Regression));
+    /// assert_eq!("median".parse(), Ok(EstimatorKind::Quantile(0.5)));
+    /// assert!("magic".parse::<EstimatorKind>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.trim().to_lowercase().as_str() {
+            "ratio" => Ok(Self::Ratio),
+            "regression" | "least_squares" => Ok(Self::Regression),
+            "median" | "quantile" => Ok(Self::Quantile(0.5)),
+            v => Err(format!("Unknown estimator: `{v}` (expected `ratio`, `regression` or `median`)")),
+        };
+    }
+}
+
 /// A struct representing the attributes used for making a prediction.
 #[derive(Debug, Default, Clone)]
 struct PredictionValues {
@@ -32,6 +105,8 @@ pub enum PredictionAttributes {
     LinesAdded,
     /// Represents the amount of lines removed in a commit.
     LinesRemoved,
+    /// Represents the amount of files renamed or copied in a commit.
+    FilesRenamed,
 }
 
 /// The struct holding data to be used in predictions.
@@ -112,15 +187,287 @@ impl PredictionStructure {
 
         for (k, v) in values {
             let pred_value = match self.history_map.get(&k) {
-                Some(v) => v,
-                None => continue,
+                // Skips attributes that can't be used as a ratio
+                Some(v) if v.sum.0 != 0 => v,
+                _ => continue,
             };
 
             results.push(pred_value.predict(v.clone()));
         }
 
+        if results.is_empty() {
+            return 0;
+        }
+
         let response = results.iter().sum::<Timestamp>() / results.len() as Timestamp;
         return response;
 
     }
 }
+
+impl StartTimeEstimator for PredictionStructure {
+    fn insert(&mut self, values: &[(PredictionAttributes, i32)], time: Timestamp) {
+        for (attribute, value) in values {
+            self.insert_item(attribute.clone(), *value, time);
+        }
+    }
+
+    fn predict(&self, values: &[(PredictionAttributes, i32)]) -> Timestamp {
+        return PredictionStructure::predict(self, values);
+    }
+}
+
+/// A multivariate least-squares regression (with an intercept) of time on every attribute.
+/// Unlike the ratio model the attributes are fitted together, so time that is explained by one
+/// attribute (e.g. lines added) isn't counted again for a correlated one (e.g. files changed.)
+/// Falls back to the ratio model (`PredictionStructure`) until it has enough samples or if the
+/// samples can't be fitted.
+/// ```rust
+/// # use git_stats_web::prediction::{PredictionAttributes, RegressionEstimator, StartTimeEstimator};
+/// let mut estimator = RegressionEstimator::new();
+/// let commit = |added: i32, removed: i32| vec![
+///     (PredictionAttributes::FilesChanged, 1),
+///     (PredictionAttributes::LinesAdded, added),
+///     (PredictionAttributes::LinesRemoved, removed),
+/// ];
+///
+/// // Every commit takes a minute plus 30 seconds per line added and 10 per line removed
+/// for i in 0..30 {
+///     let (added, removed) = (i * 3 % 17, i * 7 % 11);
+///     estimator.insert(&commit(added, removed), (60 + 30 * added + 10 * removed) as i64);
+/// }
+///
+/// assert_eq!(estimator.predict(&commit(10, 5)), 60 + 300 + 50);
+/// ```
+#[derive(Debug)]
+pub struct RegressionEstimator {
+    /// The sums of the products of every pair of features (`XᵀX`.)
+    xtx: [[f64; REGRESSION_COEFFICIENTS]; REGRESSION_COEFFICIENTS],
+    /// The sums of the products of every feature and the time (`Xᵀy`.)
+    xty: [f64; REGRESSION_COEFFICIENTS],
+    count: usize,
+    min: Timestamp,
+    max: Timestamp,
+    fallback: PredictionStructure,
+}
+
+impl RegressionEstimator {
+    /// Creates an estimator without any samples.
+    pub fn new() -> Self {
+        return Self {
+            xtx: [[0.0; REGRESSION_COEFFICIENTS]; REGRESSION_COEFFICIENTS],
+            xty: [0.0; REGRESSION_COEFFICIENTS],
+            count: 0,
+            min: Timestamp::MAX,
+            max: Timestamp::MIN,
+            fallback: PredictionStructure::new(),
+        };
+    }
+
+    /// Gets the features of a commit, starting with a constant for the intercept.
+    fn features(values: &[(PredictionAttributes, i32)]) -> [f64; REGRESSION_COEFFICIENTS] {
+        let mut features = [1.0; REGRESSION_COEFFICIENTS];
+
+        for (i, attribute) in REGRESSION_ATTRIBUTES.iter().enumerate() {
+            features[i + 1] = values
+                .iter()
+                .find(|(k, _v)| k == attribute)
+                .map(|(_k, v)| *v as f64)
+                .unwrap_or_default();
+        }
+
+        return features;
+    }
+
+    /// Solves the normal equations for the coefficients.
+    /// Returns `None` if there are too few samples or they can't be fitted.
+    fn coefficients(&self) -> Option<[f64; REGRESSION_COEFFICIENTS]> {
+
+        if self.count < REGRESSION_COEFFICIENTS * MIN_SAMPLES_PER_COEFFICIENT {
+            return None;
+        }
+
+        let mut a = self.xtx;
+        let mut b = self.xty;
+
+        // A tiny ridge keeps attributes that never change (e.g. no renames) from making the
+        // system singular, their coefficients end up as 0
+        let scale = (1..REGRESSION_COEFFICIENTS).map(|i| a[i][i]).fold(0.0, f64::max);
+        for (i, row) in a.iter_mut().enumerate().skip(1) {
+            row[i] += scale * 1e-9 + f64::EPSILON;
+        }
+
+        // Gaussian elimination with partial pivoting
+        for col in 0..REGRESSION_COEFFICIENTS {
+            let pivot = (col..REGRESSION_COEFFICIENTS).max_by(|x, y| a[*x][col].abs().total_cmp(&a[*y][col].abs()))?;
+            if a[pivot][col].abs() < f64::EPSILON {
+                return None;
+            }
+
+            a.swap(col, pivot);
+            b.swap(col, pivot);
+
+            let pivot_row = a[col];
+            for row in col + 1..REGRESSION_COEFFICIENTS {
+                let factor = a[row][col] / pivot_row[col];
+                for (value, pivot_value) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                    *value -= factor * pivot_value;
+                }
+                b[row] -= factor * b[col];
+            }
+        }
+
+        let mut coefficients = [0.0; REGRESSION_COEFFICIENTS];
+        for row in (0..REGRESSION_COEFFICIENTS).rev() {
+            let known = (row + 1..REGRESSION_COEFFICIENTS).map(|k| a[row][k] * coefficients[k]).sum::<f64>();
+            coefficients[row] = (b[row] - known) / a[row][row];
+        }
+
+        return coefficients.iter().all(|v| v.is_finite()).then_some(coefficients);
+
+    }
+}
+
+impl Default for RegressionEstimator {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl StartTimeEstimator for RegressionEstimator {
+    fn insert(&mut self, values: &[(PredictionAttributes, i32)], time: Timestamp) {
+
+        let features = Self::features(values);
+
+        for (i, x) in features.iter().enumerate() {
+            for (j, y) in features.iter().enumerate() {
+                self.xtx[i][j] += x * y;
+            }
+            self.xty[i] += x * time as f64;
+        }
+
+        self.count += 1;
+        self.min = self.min.min(time);
+        self.max = self.max.max(time);
+        StartTimeEstimator::insert(&mut self.fallback, values, time);
+
+    }
+
+    fn predict(&self, values: &[(PredictionAttributes, i32)]) -> Timestamp {
+
+        let Some(coefficients) = self.coefficients() else {
+            return self.fallback.predict(values);
+        };
+
+        let prediction = Self::features(values)
+            .iter()
+            .zip(coefficients)
+            .map(|(x, coefficient)| x * coefficient)
+            .sum::<f64>()
+            .round() as Timestamp;
+
+        // Keeps predictions within the times that were seen
+        return prediction.clamp(self.min, self.max);
+
+    }
+}
+
+/// Estimates the time of a commit from a quantile (e.g. the median) of the seconds per line
+/// changed of the samples.
+/// A few huge commits (e.g. imports or generated code) barely move a quantile, where they would
+/// dominate the sums of the ratio model.
+/// Commits without changed lines are estimated with the same quantile of the times of every
+/// sample, and estimates are capped at the longest time that was seen.
+/// ```rust
+/// # use git_stats_web::prediction::{PredictionAttributes, QuantileEstimator, StartTimeEstimator};
+/// let lines = |added: i32| vec![(PredictionAttributes::LinesAdded, added), (PredictionAttributes::LinesRemoved, 0)];
+///
+/// let mut estimator = QuantileEstimator::new(0.5);
+/// estimator.insert(&lines(10), 100);
+/// estimator.insert(&lines(20), 300);
+/// estimator.insert(&lines(30), 600);
+/// // An import that only took a minute
+/// estimator.insert(&lines(20000), 60);
+///
+/// // The median is between 10 and 15 seconds per line
+/// assert_eq!(estimator.predict(&lines(4)), 50);
+/// assert_eq!(estimator.predict(&lines(0)), 200);
+/// ```
+#[derive(Debug)]
+pub struct QuantileEstimator {
+    quantile: f64,
+    /// The seconds per line changed of every sample that changed lines.
+    seconds_per_line: Vec<f64>,
+    /// The time of every sample.
+    times: Vec<f64>,
+    /// The quantiles of the samples, computed when the first estimate is made.
+    quantiles: OnceCell<(Option<f64>, Option<f64>)>,
+}
+
+impl QuantileEstimator {
+    /// Creates an estimator without any samples.
+    /// `quantile` is clamped to `0.0..=1.0` (`0.5` is the median.)
+    pub fn new(quantile: f64) -> Self {
+        return Self {
+            quantile: quantile.clamp(0.0, 1.0),
+            seconds_per_line: Vec::new(),
+            times: Vec::new(),
+            quantiles: OnceCell::new(),
+        };
+    }
+
+    /// Gets the amount of lines changed by a commit.
+    fn lines_changed(values: &[(PredictionAttributes, i32)]) -> i64 {
+        return values
+            .iter()
+            .filter(|(k, _v)| matches!(k, PredictionAttributes::LinesAdded | PredictionAttributes::LinesRemoved))
+            .map(|(_k, v)| *v as i64)
+            .sum();
+    }
+
+    /// Gets the quantile of some values, interpolating between the closest two.
+    fn quantile_of(&self, values: &[f64]) -> Option<f64> {
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let position = (sorted.len().checked_sub(1)? as f64) * self.quantile;
+        let (low, high) = (sorted[position.floor() as usize], sorted[position.ceil() as usize]);
+
+        return Some(low + (high - low) * position.fract());
+
+    }
+}
+
+impl StartTimeEstimator for QuantileEstimator {
+    fn insert(&mut self, values: &[(PredictionAttributes, i32)], time: Timestamp) {
+
+        let lines = Self::lines_changed(values);
+        if lines > 0 {
+            self.seconds_per_line.push(time as f64 / lines as f64);
+        }
+
+        self.times.push(time as f64);
+        self.quantiles = OnceCell::new();
+
+    }
+
+    fn predict(&self, values: &[(PredictionAttributes, i32)]) -> Timestamp {
+
+        let (seconds_per_line, time) = *self.quantiles.get_or_init(|| (
+            self.quantile_of(&self.seconds_per_line),
+            self.quantile_of(&self.times),
+        ));
+
+        let lines = Self::lines_changed(values);
+
+        let prediction = match seconds_per_line {
+            Some(v) if lines > 0 => v * lines as f64,
+            _ => time.unwrap_or_default(),
+        };
+
+        let longest = self.times.iter().copied().fold(0.0, f64::max);
+        return prediction.min(longest).round() as Timestamp;
+
+    }
+}
@@ -0,0 +1,102 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use super::cli::CliArgs;
+
+/// The prefix of encrypted values, so they can be told apart from values stored before
+/// encryption was added.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+/// The length of the nonce stored in front of the ciphertext (in bytes.)
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts the secrets users store in the database (access tokens and ssh keys) with a key only
+/// the server knows, so a leaked database doesn't leak them.
+pub struct SecretCipher {
+    cipher: Option<Aes256Gcm>,
+}
+
+impl SecretCipher {
+
+    /// Creates a cipher with a key derived from `secret`.
+    /// Nothing can be encrypted or decrypted without a secret.
+    /// ```rust
+    /// # use git_stats_web::secrets::SecretCipher;
+    /// let cipher = SecretCipher::new(Some("server secret"));
+    ///
+    /// let encrypted = cipher.encrypt("token").unwrap();
+    /// assert_ne!(encrypted, "token");
+    /// assert_eq!(cipher.decrypt(&encrypted).as_deref(), Some("token"));
+    ///
+    /// // Other secrets can't decrypt it
+    /// assert_eq!(SecretCipher::new(Some("other secret")).decrypt(&encrypted), None);
+    /// assert_eq!(SecretCipher::new(None).encrypt("token"), None);
+    /// ```
+    pub fn new(secret: Option<&str>) -> Self {
+        return Self {
+            cipher: secret
+                .filter(|v| !v.is_empty())
+                .map(|v| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&Sha256::digest(v.as_bytes())))),
+        };
+    }
+
+    /// Creates the cipher from the CLI arguments (see `--secrets-key`.)
+    pub fn from_args(args: &CliArgs) -> Self {
+        return Self::new(args.secrets_key.as_deref());
+    }
+
+    /// Returns true if a secret is configured.
+    pub fn is_enabled(&self) -> bool {
+        return self.cipher.is_some();
+    }
+
+    /// Encrypts a value with a random nonce.
+    /// Returns `None` if no secret is configured.
+    pub fn encrypt(&self, value: &str) -> Option<String> {
+
+        let cipher = self.cipher.as_ref()?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, value.as_bytes()).ok()?;
+
+        return Some(format!("{ENCRYPTED_PREFIX}{}", STANDARD.encode([nonce.as_slice(), &ciphertext].concat())));
+
+    }
+
+    /// Decrypts a value made by `encrypt()`.
+    /// Values stored before encryption was added are returned as they are.
+    /// Returns `None` if the value can't be decrypted (e.g. the secret changed.)
+    pub fn decrypt(&self, value: &str) -> Option<String> {
+
+        let Some(encoded) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Some(value.to_string());
+        };
+
+        let Some(cipher) = self.cipher.as_ref() else {
+            warn!("Can't decrypt a stored secret without `--secrets-key`!");
+            return None;
+        };
+
+        let bytes = STANDARD.decode(encoded).ok().filter(|v| v.len() > NONCE_LENGTH)?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+
+        return match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            Ok(v) => String::from_utf8(v).ok(),
+            Err(_) => {
+                warn!("Can't decrypt a stored secret! (was `--secrets-key` changed?)");
+                None
+            },
+        };
+
+    }
+
+    /// Returns true if a value is already encrypted.
+    pub fn is_encrypted(value: &str) -> bool {
+        return value.starts_with(ENCRYPTED_PREFIX);
+    }
+
+}
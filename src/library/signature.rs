@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use git2::{ErrorCode, Oid, Repository};
+use log::{debug, warn};
+use rand::{thread_rng, Rng};
+
+use super::{calendar::SignatureStatus, cli::CliArgs};
+
+/// The first line of an armored GPG signature.
+const GPG_SIGNATURE_HEADER: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+/// The first line of an armored SSH signature.
+const SSH_SIGNATURE_HEADER: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+
+/// The namespace git signs commits in with SSH keys.
+const SSH_NAMESPACE: &str = "git";
+
+/// Checks the signatures of commits against the keys the server trusts.
+/// GPG signatures are verified with `gpgv` and SSH signatures with `ssh-keygen`.
+/// Verifying a signature starts a process so the results are cached until one of the keyrings
+/// changes.
+#[derive(Debug)]
+pub struct SignatureVerifier {
+    gpg_keyring: Option<PathBuf>,
+    ssh_allowed_signers: Option<PathBuf>,
+    cache: Mutex<SignatureCache>,
+}
+
+/// The verified signatures of a version of the keyrings.
+#[derive(Debug, Default)]
+struct SignatureCache {
+    keyring_version: Vec<Option<SystemTime>>,
+    statuses: HashMap<Oid, SignatureStatus>,
+}
+
+impl SignatureVerifier {
+
+    /// Creates a verifier that trusts the keys in the given files.
+    /// Signatures can't be verified without a keyring (every signed commit is reported as
+    /// `Signed`.)
+    pub fn new(gpg_keyring: Option<PathBuf>, ssh_allowed_signers: Option<PathBuf>) -> Self {
+        return Self {
+            gpg_keyring,
+            ssh_allowed_signers,
+            cache: Mutex::new(SignatureCache::default()),
+        };
+    }
+
+    /// Creates the verifier from the CLI arguments.
+    /// The keyrings need absolute paths since `gpgv` reads relative paths from its home directory.
+    pub fn from_args(args: &CliArgs) -> Self {
+
+        let absolute_path = |path: &String| match fs::canonicalize(path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Can't use keyring: `{}` (error: {})", path, e);
+                None
+            },
+        };
+
+        return Self::new(
+            args.gpg_keyring.as_ref().and_then(absolute_path),
+            args.ssh_allowed_signers.as_ref().and_then(absolute_path),
+        );
+
+    }
+
+    /// Gets the signature status of a commit.
+    /// ```rust
+    /// # use git_stats_web::{calendar::SignatureStatus, signature::SignatureVerifier};
+    /// # use git2::{Repository, Signature};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let repo = Repository::init(dir.path()).unwrap();
+    /// let signature = Signature::now("Alice", "alice@example.com").unwrap();
+    /// # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+    /// let unsigned = repo.commit(None, &signature, &signature, "Unsigned", &tree, &[]).unwrap();
+    ///
+    /// // Creates a commit with a signature no keyring trusts
+    /// let content = repo.commit_create_buffer(&signature, &signature, "Signed", &tree, &[]).unwrap();
+    /// let pgp = "-----BEGIN PGP SIGNATURE-----\n\nAAAA\n-----END PGP SIGNATURE-----";
+    /// let signed = repo.commit_signed(content.as_str().unwrap(), pgp, None).unwrap();
+    ///
+    /// let verifier = SignatureVerifier::new(None, None);
+    /// assert_eq!(verifier.status(&repo, unsigned), SignatureStatus::Unsigned);
+    /// assert_eq!(verifier.status(&repo, signed), SignatureStatus::Signed);
+    /// ```
+    pub fn status(&self, repo: &Repository, oid: Oid) -> SignatureStatus {
+
+        let (signature, signed_data) = match repo.extract_signature(&oid, None) {
+            Ok(v) => v,
+            Err(e) => {
+                if e.code() != ErrorCode::NotFound {
+                    debug!("Can't read the signature of commit `{}` Error: {}", oid, e.message());
+                }
+                return SignatureStatus::Unsigned;
+            },
+        };
+
+        // Forgets every result once a keyring changes
+        let keyring_version = self.keyring_version();
+        {
+            let mut cache = self.lock_cache();
+            if cache.keyring_version != keyring_version {
+                *cache = SignatureCache {
+                    keyring_version: keyring_version.clone(),
+                    statuses: HashMap::new(),
+                };
+            }
+
+            if let Some(status) = cache.statuses.get(&oid) {
+                return status.clone();
+            }
+        }
+
+        // The cache isn't locked while verifying, so other requests don't wait on the processes
+        let signer = match signature.as_ref() {
+            v if v.starts_with(GPG_SIGNATURE_HEADER) => self.verify_gpg(oid, &signature, &signed_data),
+            v if v.starts_with(SSH_SIGNATURE_HEADER) => self.verify_ssh(oid, &signature, &signed_data),
+            _ => None,
+        };
+
+        let status = match signer {
+            Some(signer) => SignatureStatus::Verified { signer },
+            None => SignatureStatus::Signed,
+        };
+
+        // Results for a keyring that changed in the meantime aren't cached
+        let mut cache = self.lock_cache();
+        if cache.keyring_version == keyring_version {
+            cache.statuses.insert(oid, status.clone());
+        }
+
+        return status;
+
+    }
+
+    /// Locks the cache of verified signatures.
+    fn lock_cache(&self) -> MutexGuard<'_, SignatureCache> {
+        return match self.cache.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+    }
+
+    /// Gets the modification times of the keyrings.
+    fn keyring_version(&self) -> Vec<Option<SystemTime>> {
+        return [&self.gpg_keyring, &self.ssh_allowed_signers]
+            .into_iter()
+            .map(|path| path.as_ref().and_then(|v| fs::metadata(v).and_then(|v| v.modified()).ok()))
+            .collect();
+    }
+
+    /// Verifies a GPG signature, returning the user id of the key that made it.
+    fn verify_gpg(&self, oid: Oid, signature: &[u8], signed_data: &[u8]) -> Option<String> {
+
+        let keyring = self.gpg_keyring.as_ref()?;
+        let signature_file = SignatureFile::new(oid, signature)?;
+
+        let output = run_with_input(
+            Command::new("gpgv")
+                .arg("--status-fd=1")
+                .arg("--keyring").arg(keyring)
+                .arg(&signature_file.0)
+                .arg("-"),
+            signed_data,
+        )?;
+
+        // e.g. `[GNUPG:] GOODSIG 0123456789ABCDEF Alice <alice@example.com>`
+        return output
+            .lines()
+            .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+            .map(|v| v.split_once(' ').map(|(_key_id, user_id)| user_id).unwrap_or(v).to_string());
+
+    }
+
+    /// Verifies an SSH signature, returning the principal of the key that made it.
+    fn verify_ssh(&self, oid: Oid, signature: &[u8], signed_data: &[u8]) -> Option<String> {
+
+        let allowed_signers = self.ssh_allowed_signers.as_ref()?;
+        let signature_file = SignatureFile::new(oid, signature)?;
+
+        let principals = run_with_input(
+            Command::new("ssh-keygen")
+                .args(["-Y", "find-principals", "-f"]).arg(allowed_signers)
+                .arg("-s").arg(&signature_file.0),
+            &[],
+        )?;
+
+        // The key can be listed for several principals, any of them can verify the signature
+        for principal in principals.lines().map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            let verified = run_with_input(
+                Command::new("ssh-keygen")
+                    .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-f"]).arg(allowed_signers)
+                    .arg("-I").arg(principal)
+                    .arg("-s").arg(&signature_file.0),
+                signed_data,
+            );
+
+            if verified.is_some() {
+                return Some(principal.to_string());
+            }
+        }
+
+        return None;
+
+    }
+
+}
+
+/// A signature written to a temporary file (the tools can't read it from stdin along with the
+/// signed data.) The file is deleted when this is dropped.
+struct SignatureFile(PathBuf);
+
+impl SignatureFile {
+    /// Writes the signature to a new file with a random name that only the server can read.
+    /// Files that already exist are never written to, so other users of the temp directory
+    /// can't swap in a symlink or read the signature.
+    fn new(oid: Oid, signature: &[u8]) -> Option<Self> {
+        let path = std::env::temp_dir().join(format!("git-stats-signature-{}-{}-{:016x}.sig", std::process::id(), oid, thread_rng().gen::<u64>()));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = match options.open(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Can't create signature file: `{}` Error: {:?}", path.display(), e);
+                return None;
+            },
+        };
+
+        // Deletes the file from here on, even if it can't be written
+        let signature_file = Self(path);
+
+        if let Err(e) = file.write_all(signature) {
+            warn!("Can't write signature file: `{}` Error: {:?}", signature_file.0.display(), e);
+            return None;
+        }
+
+        return Some(signature_file);
+    }
+}
+
+impl Drop for SignatureFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs a command with `input` as its stdin.
+/// Returns its stdout if it exits successfully.
+fn run_with_input(command: &mut Command, input: &[u8]) -> Option<String> {
+
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Can't run `{:?}` Error: {:?}", command.get_program(), e);
+            return None;
+        },
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input);
+    }
+
+    let output = child.wait_with_output().ok()?;
+
+    if !output.status.success() {
+        debug!("`{:?}` failed with: {}", command.get_program(), output.status);
+        return None;
+    }
+
+    return Some(String::from_utf8_lossy(&output.stdout).to_string());
+
+}
+
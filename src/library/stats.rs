@@ -0,0 +1,337 @@
+use std::{collections::HashMap, path::Path};
+
+use git2::{AttrCheckFlags, AttrValue, Commit, Delta, Diff, Index, ObjectType, Oid, Patch, Pathspec, PathspecFlags, Repository, TreeWalkMode, TreeWalkResult};
+
+use super::{
+    aliases::Timestamp,
+    calendar::ExcludedChanges,
+    filter::{PathFilter, StatsExclusion},
+    prediction::PredictionAttributes,
+};
+
+/// How a file in a diff is counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileClass {
+    /// The file is counted in the metrics.
+    Counted,
+    /// The file is counted separately from the metrics (see `StatsExclusion`.)
+    Excluded,
+    /// The file isn't counted at all (see `PathFilter::exclude`.)
+    Ignored,
+}
+
+/// Gets the ids of the `.gitattributes` files in the HEAD commit that set linguist attributes
+/// (empty if HEAD is unborn.)
+/// Linguist attributes are only read from the HEAD commit (see `FileClassifier`), so these
+/// identify the attributes the stats of a repo were computed with.
+pub fn linguist_attribute_files(repo: &Repository) -> Result<Vec<Oid>, git2::Error> {
+
+    let tree = match repo.head().and_then(|v| v.peel_to_tree()) {
+        Ok(v) => v,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut files = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |_dir, entry| {
+        let is_attributes = entry.name() == Some(".gitattributes") && entry.kind() == Some(ObjectType::Blob);
+        if is_attributes && repo.find_blob(entry.id()).is_ok_and(|v| v.content().windows(9).any(|w| w == b"linguist-")) {
+            files.push(entry.id());
+        }
+        return TreeWalkResult::Ok;
+    })?;
+
+    return Ok(files);
+
+}
+
+/// Decides how the files in the diffs of a repository are counted.
+pub struct FileClassifier {
+    ignored: Option<Pathspec>,
+    excluded: Option<Pathspec>,
+    /// A separate handle of the repository with the HEAD commit as its index, used for reading
+    /// the linguist attributes. Unset if they're disabled or the HEAD commit doesn't set any.
+    linguist: Option<Repository>,
+}
+
+impl FileClassifier {
+
+    /// Creates a classifier for the files of a repository.
+    pub fn new(repo: &Repository, path_filter: &PathFilter, exclusion: &StatsExclusion) -> Result<Self, git2::Error> {
+
+        // libgit2 doesn't support negative pathspecs when diffing so excluded files are filtered
+        // out separately
+        let pathspec = |patterns: &Vec<String>| match patterns.is_empty() {
+            true => Ok(None),
+            false => Pathspec::new(patterns).map(Some),
+        };
+
+        // The attributes of the working directory (or the real index) could differ from the
+        // commit and would be cached with the stats, so an in-memory index of the HEAD commit is
+        // used instead
+        let linguist = match exclusion.linguist && !linguist_attribute_files(repo)?.is_empty() {
+            true => {
+                let attributes_repo = Repository::open(repo.path())?;
+                let mut index = Index::new()?;
+                index.read_tree(&repo.head()?.peel_to_tree()?)?;
+                attributes_repo.set_index(&mut index)?;
+                Some(attributes_repo)
+            },
+            false => None,
+        };
+
+        return Ok(Self {
+            ignored: pathspec(&path_filter.exclude)?,
+            excluded: pathspec(&exclusion.patterns)?,
+            linguist,
+        });
+
+    }
+
+    /// Returns true if every file gets counted.
+    pub fn is_empty(&self) -> bool {
+        return self.ignored.is_none() && self.excluded.is_none() && self.linguist.is_none();
+    }
+
+    /// Gets the class of a file from its path.
+    /// The linguist attributes are read from the `.gitattributes` files of the HEAD commit.
+    pub fn classify(&self, path: &Path) -> FileClass {
+
+        let matches = |pathspec: &Option<Pathspec>| pathspec
+            .as_ref()
+            .is_some_and(|v| v.matches_path(path, PathspecFlags::DEFAULT));
+
+        if matches(&self.ignored) {
+            return FileClass::Ignored;
+        }
+
+        if let Some(attributes_repo) = &self.linguist {
+            let attributes = ["linguist-generated", "linguist-vendored"].map(|name| {
+                match attributes_repo.get_attr(path, name, AttrCheckFlags::INDEX_ONLY) {
+                    Ok(v) => AttrValue::from_string(v),
+                    Err(_) => AttrValue::Unspecified,
+                }
+            });
+
+            if attributes.iter().any(|v| matches!(v, AttrValue::True)) {
+                return FileClass::Excluded;
+            }
+
+            // Explicitly unsetting the attributes overrides the patterns
+            if attributes.iter().any(|v| matches!(v, AttrValue::False)) {
+                return FileClass::Counted;
+            }
+        }
+
+        if matches(&self.excluded) {
+            return FileClass::Excluded;
+        }
+
+        return FileClass::Counted;
+
+    }
+
+}
+
+/// The diff statistics of a single commit.
+/// These are expensive to compute so they get cached in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitStats {
+    /// The id of the commit.
+    pub oid: Oid,
+    /// The name of the author of the commit.
+    pub author_name: String,
+    /// The email of the author of the commit.
+    pub author_email: String,
+    /// The epoch timestamp of the commit.
+    pub timestamp: Timestamp,
+    /// The amount of files changed in the commit (not counting renamed or copied files.)
+    pub files_changed: usize,
+    /// The amount of files renamed or copied in the commit. Their changes are still counted in
+    /// the insertions and deletions.
+    pub files_renamed: usize,
+    /// The amount of lines added in the commit.
+    pub insertions: usize,
+    /// The amount of lines removed in the commit.
+    pub deletions: usize,
+    /// The changes that aren't included in the counts above.
+    pub excluded: ExcludedChanges,
+}
+
+impl CommitStats {
+
+    /// Creates empty stats (no changes) for a commit.
+    pub fn new(commit: &Commit) -> Self {
+
+        let author = commit.author();
+
+        return Self {
+            oid: commit.id(),
+            author_name: author.name().unwrap_or_default().to_string(),
+            author_email: author.email().unwrap_or_default().to_string(),
+            timestamp: commit.time().seconds(),
+            files_changed: 0,
+            files_renamed: 0,
+            insertions: 0,
+            deletions: 0,
+            excluded: ExcludedChanges::default(),
+        };
+
+    }
+
+    /// Creates the stats of a commit from its diff.
+    /// Files are counted according to their class in `classifier`.
+    /// Renames and copies are only counted as such if they were detected with
+    /// `Diff::find_similar()` beforehand.
+    pub fn from_diff(commit: &Commit, diff: &Diff, classifier: &FileClassifier) -> Result<Self, git2::Error> {
+
+        let mut stats = Self::new(commit);
+
+        let is_rename = |status: Delta| matches!(status, Delta::Renamed | Delta::Copied);
+
+        let classes = diff.deltas().map(|delta| {
+            let classes = [delta.old_file().path(), delta.new_file().path()]
+                .into_iter()
+                .flatten()
+                .map(|path| classifier.classify(path))
+                .collect::<Vec<FileClass>>();
+
+            return match classes.contains(&FileClass::Ignored) {
+                true => FileClass::Ignored,
+                false if classes.contains(&FileClass::Excluded) => FileClass::Excluded,
+                false => FileClass::Counted,
+            };
+        }).collect::<Vec<FileClass>>();
+
+        // The stats of the whole diff are much cheaper than a patch per file
+        if classes.iter().all(|v| *v == FileClass::Counted) {
+            let diff_stats = diff.stats()?;
+            stats.files_renamed = diff.deltas().filter(|v| is_rename(v.status())).count();
+            stats.files_changed = diff_stats.files_changed() - stats.files_renamed;
+            stats.insertions = diff_stats.insertions();
+            stats.deletions = diff_stats.deletions();
+            return Ok(stats);
+        }
+
+        // Counts the files one by one so the excluded ones are counted separately
+        for (idx, (delta, class)) in diff.deltas().zip(classes).enumerate() {
+
+            if class == FileClass::Ignored {
+                continue;
+            }
+
+            let (insertions, deletions) = match Patch::from_diff(diff, idx)? {
+                Some(patch) => {
+                    let (_context, insertions, deletions) = patch.line_stats()?;
+                    (insertions, deletions)
+                },
+                None => (0, 0),
+            };
+
+            match class {
+                FileClass::Counted => {
+                    match is_rename(delta.status()) {
+                        true => stats.files_renamed += 1,
+                        false => stats.files_changed += 1,
+                    }
+                    stats.insertions += insertions;
+                    stats.deletions += deletions;
+                },
+                // Ignored files were already skipped
+                _ => {
+                    stats.excluded.files_changed += 1;
+                    stats.excluded.insertions += insertions;
+                    stats.excluded.deletions += deletions;
+                },
+            }
+
+        }
+
+        return Ok(stats);
+
+    }
+
+    /// Gets the attributes used for making predictions from the stats.
+    /// Renames are only included when there are any, so commits without them are predicted the
+    /// same as before rename detection.
+    pub fn prediction_attributes(&self) -> Vec<(PredictionAttributes, i32)> {
+
+        let mut attributes = vec![
+            (PredictionAttributes::FilesChanged, self.files_changed as i32),
+            (PredictionAttributes::LinesAdded, self.insertions as i32),
+            (PredictionAttributes::LinesRemoved, self.deletions as i32),
+        ];
+
+        if self.files_renamed > 0 {
+            attributes.push((PredictionAttributes::FilesRenamed, self.files_renamed as i32));
+        }
+
+        return attributes;
+
+    }
+
+}
+
+/// A cache of commit stats for a single repository.
+/// Keeps track of which stats were added since the cache was loaded so only those have to be
+/// written back to the database.
+#[derive(Debug, Default)]
+pub struct StatsCache {
+    entries: HashMap<Oid, CommitStats>,
+    new_entries: Vec<Oid>,
+}
+
+impl StatsCache {
+
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Gets the stats of a commit if they are cached.
+    pub fn get(&self, oid: &Oid) -> Option<&CommitStats> {
+        return self.entries.get(oid);
+    }
+
+    /// Adds newly computed stats to the cache.
+    /// ```rust
+    /// # use git_stats_web::stats::{CommitStats, StatsCache};
+    /// # use git2::Oid;
+    /// let stats = CommitStats {
+    ///     oid: Oid::zero(),
+    ///     author_name: "Alice".to_string(),
+    ///     author_email: "alice@example.com".to_string(),
+    ///     timestamp: 0,
+    ///     files_changed: 1,
+    ///     files_renamed: 0,
+    ///     insertions: 2,
+    ///     deletions: 3,
+    ///     excluded: Default::default(),
+    /// };
+    ///
+    /// let mut cache = StatsCache::from_iter(vec![stats.clone()]);
+    /// assert_eq!(cache.new_stats().count(), 0); // loaded stats aren't new
+    ///
+    /// cache.insert(stats);
+    /// assert_eq!(cache.new_stats().count(), 1);
+    /// ```
+    pub fn insert(&mut self, stats: CommitStats) {
+        self.new_entries.push(stats.oid);
+        self.entries.insert(stats.oid, stats);
+    }
+
+    /// Gets the stats added since the cache was loaded.
+    pub fn new_stats(&self) -> impl Iterator<Item = &CommitStats> {
+        return self.new_entries.iter().filter_map(|oid| self.entries.get(oid));
+    }
+
+}
+
+impl FromIterator<CommitStats> for StatsCache {
+    /// Creates a cache from previously stored stats. None of these are considered new.
+    fn from_iter<T: IntoIterator<Item = CommitStats>>(iter: T) -> Self {
+        return Self {
+            entries: iter.into_iter().map(|v| (v.oid, v)).collect(),
+            new_entries: Vec::new(),
+        };
+    }
+}
@@ -1,19 +1,160 @@
-use std::{collections::BTreeSet, sync::Arc};
+use std::{cmp::Reverse, collections::HashSet, sync::Arc};
 
+use actix_web::http::StatusCode;
+use chrono::{DateTime, FixedOffset, NaiveTime};
 use regex::Regex;
 use url::Url;
 use log::debug;
 
-use git2::{Commit, DiffOptions, Oid, Repository};
+use git2::{DiffFindOptions, DiffOptions, Mailmap, ObjectType, Oid, Repository};
 
 use crate::{
-    aliases::AnnotatedCalendarValue,
+    aliases::{AnnotatedCalendarValue, Timestamp},
     cli::CliArgs,
-    prediction::{PredictionAttributes, PredictionStructure},
-    calendar::CalendarValue,
-    git,
+    errors::AppError,
+    filter::{IdentityFilter, PathFilter, PredictionScope, StatsExclusion},
+    prediction::{EstimatorKind, StartTimeEstimator},
+    calendar::{CalendarValue, Report, SignatureStatus},
+    signature::SignatureVerifier,
+    stats::{self, CommitStats, FileClassifier, StatsCache},
+    git::{self, IdentityAlias, MergePolicy, RefSelection},
 };
 
+/// The version of how commit stats are computed.
+/// Should be bumped whenever a change makes previously cached stats wrong.
+const STATS_VERSION: u32 = 2;
+
+/// How much older than `AnalysisOptions::stop_at` a commit has to be before the walk stops (in
+/// seconds.) Commits with skewed clocks can be older than commits walked after them.
+const STOP_AT_CLOCK_SKEW: Timestamp = 24 * 60 * 60;
+
+/// Per-request options for how a repository gets analyzed.
+#[derive(Debug, Clone)]
+pub struct AnalysisOptions {
+    /// The references to start walking commits from.
+    pub refs: RefSelection,
+    /// The maximum amount of commits to read. `None` means every commit gets read.
+    pub max_commits: Option<usize>,
+    /// The epoch timestamp to stop walking at. Commits older than this aren't read.
+    pub stop_at: Option<Timestamp>,
+    /// The amount of time in seconds that is allowed between commits in the same session.
+    pub time_allowed: Timestamp,
+    /// The epoch timestamp of the start of the report. Older commits aren't returned.
+    pub date_start: Option<Timestamp>,
+    /// The epoch timestamp of the end of the report (exclusive.) Newer commits aren't returned.
+    pub date_end: Option<Timestamp>,
+    /// The timezone of the viewer. Sessions are split at midnight in this timezone so each day of
+    /// the report only counts its own time. Nothing is split if unset.
+    /// This is a fixed offset, so daylight saving changes within the report aren't followed.
+    pub timezone: Option<FixedOffset>,
+    /// Only commits by matching authors are returned.
+    pub author_filter: IdentityFilter,
+    /// Only commits by matching committers are returned.
+    pub committer_filter: IdentityFilter,
+    /// Sets if predictions are made from every commit or only the commits passing the filters.
+    pub prediction_scope: PredictionScope,
+    /// Identity aliases used on top of the repo's `.mailmap`.
+    pub aliases: Vec<IdentityAlias>,
+    /// Limits which files get counted.
+    pub path_filter: PathFilter,
+    /// The files that are counted separately from the metrics.
+    pub stats_exclusion: StatsExclusion,
+    /// How merge commits are counted.
+    pub merge_policy: MergePolicy,
+    /// Uses the start times set in the repo's notes instead of predicting them.
+    pub start_overrides: bool,
+    /// The model that predicts the start times of sessions.
+    pub estimator: EstimatorKind,
+    /// Checks the signatures of the reported commits. The server shares its verifier between
+    /// requests so verified signatures stay cached.
+    pub signatures: Arc<SignatureVerifier>,
+}
+
+impl AnalysisOptions {
+
+    /// Creates the default options from the server's CLI arguments.
+    pub fn new(args: &CliArgs) -> Self {
+        return Self {
+            refs: RefSelection::default(),
+            max_commits: Some(args.max_commits),
+            stop_at: None,
+            time_allowed: args.time_allowed,
+            date_start: None,
+            date_end: None,
+            timezone: None,
+            author_filter: IdentityFilter::default(),
+            committer_filter: IdentityFilter::default(),
+            prediction_scope: PredictionScope::default(),
+            aliases: Vec::new(),
+            path_filter: PathFilter::default(),
+            stats_exclusion: StatsExclusion::new(&args.stats_exclude, true),
+            merge_policy: MergePolicy::default(),
+            start_overrides: true,
+            estimator: EstimatorKind::default(),
+            signatures: Arc::new(SignatureVerifier::new(None, None)),
+        };
+    }
+
+    /// Gets a key identifying the options that change the diff stats of commits.
+    /// Stats are only cached and reused for options with the same key.
+    /// The key starts with `STATS_VERSION` so stats cached by older versions aren't reused.
+    /// The linguist attributes of `repo` are part of the key, so stats get computed again once
+    /// they change.
+    pub fn stats_key(&self, repo: &Repository) -> String {
+
+        // Only diffing against the merge base changes the stats of merges
+        let merges = match self.merge_policy {
+            MergePolicy::MergeBase => "merges=merge_base".to_string(),
+            _ => String::new(),
+        };
+
+        let attributes = match self.stats_exclusion.linguist {
+            true => stats::linguist_attribute_files(repo).unwrap_or_default(),
+            false => Vec::new(),
+        };
+        let attributes = match attributes.is_empty() {
+            true => String::new(),
+            false => {
+                let ids = attributes.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",");
+                format!("attributes={}", Oid::hash_object(ObjectType::Blob, ids.as_bytes()).unwrap_or(Oid::zero()))
+            },
+        };
+
+        return [format!("v{STATS_VERSION}"), self.path_filter.cache_key(), self.stats_exclusion.cache_key(), attributes, merges]
+            .into_iter()
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<String>>()
+            .join(";");
+
+    }
+
+    /// Checks if a timestamp is within the report window.
+    /// ```rust
+    /// # use git_stats_web::{cli::CliArgs, utils::AnalysisOptions};
+    /// # use clap::Parser;
+    /// let mut options = AnalysisOptions::new(&CliArgs::parse_from(["git-stats-web"]));
+    /// options.date_start = Some(100);
+    /// options.date_end = Some(200);
+    /// assert!(options.in_window(100));
+    /// assert!(!options.in_window(200)); // the end is exclusive
+    /// assert!(!options.in_window(99));
+    /// ```
+    pub fn in_window(&self, timestamp: Timestamp) -> bool {
+        let after_start = match self.date_start {
+            Some(start) => start <= timestamp,
+            None => true,
+        };
+
+        let before_end = match self.date_end {
+            Some(end) => timestamp < end,
+            None => true,
+        };
+
+        return after_start && before_end;
+    }
+
+}
+
 /// Result type for methods which update an external source and can fail.
 /// This should generally be used when your function signature looks something like:
 /// `fn foo(self) -> UpdateResult<T, T>`
@@ -122,94 +263,476 @@ pub fn get_path(src_url: &str) -> Url {
     return Url::parse(&tmp_url).unwrap();
 }
 
+/// The commits found by `search_trees()`, the estimator trained on them and the ids of the walked commits
+type SearchResult = (Vec<AnnotatedCalendarValue>, Box<dyn StartTimeEstimator>, HashSet<Oid>);
+
 /// Function for getting all the commit data from a repository.
-fn recurs_search_trees(args: Arc<CliArgs>, repo: &Repository, commit: Commit, out_vec: &mut Vec<AnnotatedCalendarValue>, searched_commits: &mut BTreeSet<Oid>, out_pred_struct: &mut PredictionStructure) -> () {
+/// Walks every commit reachable from `tips` iteratively (newest first) and stops once
+/// `options.max_commits` commits have been read. Commits older than `options.stop_at` are skipped
+/// (without counting against `options.max_commits`) and the walk stops once they're older than
+/// `STOP_AT_CLOCK_SKEW` beyond it.
+/// Diff stats are read from `stats_cache` when possible and newly computed stats get added to it.
+/// Identities are canonicalized with `mailmap` before they get filtered or reported.
+/// The time of a commit is measured from its first parent, or from the previous commit in the
+/// walk that passes the identity filters if there are any.
+/// `progress` is called with the amount of commits read so far.
+/// Returns the commits that were found along with the estimator (`options.estimator`) trained on
+/// them and the ids of every walked commit that isn't older than `options.stop_at`.
+fn search_trees(repo: &Repository, tips: &[(String, Oid)], options: &AnalysisOptions, mailmap: &Mailmap, stats_cache: &mut StatsCache, progress: &mut dyn FnMut(usize)) -> Result<SearchResult, git2::Error> {
+
+    let mut out_vec: Vec<AnnotatedCalendarValue> = Vec::new();
+    let mut out_estimator = options.estimator.create();
+
+    // With identity filters the gaps are measured between the matching commits (see below), so
+    // the time since someone else's commit isn't credited to the filtered authors
+    let per_author = !options.author_filter.is_empty() || !options.committer_filter.is_empty();
+    let mut out_markers = Vec::new();
+
+    let revwalk = git::revwalk_from_tips(repo, tips)?;
+    let mut branch_tracker = git::BranchTracker::new(tips);
+    let mut walked = HashSet::new();
+
+    let classifier = FileClassifier::new(repo, &options.path_filter, &options.stats_exclusion)?;
+
+    let mut commit_count = 0;
+
+    for oid in revwalk {
+
+        if options.max_commits.is_some_and(|max| commit_count >= max) {
+            debug!("Stopping commit walk after {commit_count} commits!");
+            break;
+        }
 
-    // Tries to insert commit OID but if this isn't possible
-    // the just return. (see `BTreeSet::insert()` docs)
-    if !searched_commits.insert(commit.id()) {
-        return;
+        let commit = repo.find_commit(oid?)?;
+        let branches = branch_tracker.visit(&commit);
+
+        // Newer commits can still follow an older one if clocks were skewed, so the walk only
+        // stops once the commits are well past the cut-off
+        if let Some(stop_at) = options.stop_at {
+            if commit.time().seconds() < stop_at - STOP_AT_CLOCK_SKEW {
+                debug!("Stopping commit walk at commit `{}` (older than the cut-off date)", commit.id());
+                break;
+            }
+
+            if commit.time().seconds() < stop_at {
+                continue;
+            }
+        }
+
+        progress(commit_count);
+        commit_count += 1;
+
+        walked.insert(commit.id());
+
+        let is_merge = commit.parent_count() > 1;
+
+        if is_merge && options.merge_policy == MergePolicy::Skip {
+            continue;
+        }
+
+        // Canonicalizes identities so aliases get filtered and reported as the same person
+        let author = commit.author_with_mailmap(mailmap)?;
+        let committer = commit.committer_with_mailmap(mailmap)?;
+        let co_authors = git::get_co_authors(commit.message().unwrap_or_default(), mailmap);
+
+        // Co-authors get credit for the commit too
+        let passes_author_filter = options.author_filter.matches(author.name().unwrap_or_default(), author.email().unwrap_or_default())
+            || co_authors.iter().any(|v| options.author_filter.matches(v.name().unwrap_or_default(), v.email().unwrap_or_default()));
+        let passes_filters = passes_author_filter
+            && options.committer_filter.matches(committer.name().unwrap_or_default(), committer.email().unwrap_or_default());
+
+        // Commits that are filtered out are only needed for training the team's model
+        if !passes_filters && options.prediction_scope == PredictionScope::Filtered {
+            continue;
+        }
+
+        let is_marker = is_merge && options.merge_policy == MergePolicy::Marker;
+
+        // Only diffs commits that haven't been cached yet
+        let stats = match (is_marker, stats_cache.get(&commit.id())) {
+            (true, _) => CommitStats::new(&commit),
+            (false, Some(v)) => v.clone(),
+            (false, None) => {
+                let mut diff_opts = DiffOptions::new();
+
+                for pathspec in &options.path_filter.include {
+                    diff_opts.pathspec(pathspec);
+                }
+
+                let mut diff = repo.diff_tree_to_tree(
+                    git::get_base_tree(repo, &commit, options.merge_policy)?.as_ref(),
+                    Some(&commit.tree()?),
+                    Some(
+                        diff_opts
+                            .force_text(true)
+                        )
+                    )?;
+
+                // Detects moved files so they aren't counted as every line being rewritten
+                diff.find_similar(Some(
+                    DiffFindOptions::new()
+                        .renames(true)
+                        .copies(true)
+                    ))?;
+
+                let stats = CommitStats::from_diff(&commit, &diff, &classifier)?;
+                stats_cache.insert(stats.clone());
+                stats
+            },
+        };
+
+        // Skips commits that don't touch any of the counted files
+        if !options.path_filter.is_empty() && stats.files_changed + stats.files_renamed == 0 {
+            continue;
+        }
+
+        let timestamp = commit.time().seconds();
+
+        // Markers take no time and root commits always start a new session
+        let delta_t = match (is_marker, commit.parent(0)) {
+            (true, _) => 0,
+            (false, Ok(parent)) => timestamp - parent.time().seconds(),
+            (false, Err(_)) => options.time_allowed,
+        };
+        let prev_timestamp = timestamp - delta_t;
+
+        let commit_data: AnnotatedCalendarValue = (
+            CalendarValue {
+                title: commit.message().unwrap_or("MESSAGE_NOT_FOUND").trim().to_string(),
+                commit: commit.id().to_string(),
+                author: author.to_string(),
+                co_authors: co_authors.iter().map(|v| v.to_string()).collect(),
+                repo: String::new(),
+                delta_t,
+                start: prev_timestamp,
+                end: timestamp,
+                projected: false,
+                overridden: false,
+                offset_minutes: commit.time().offset_minutes(),
+                branches,
+                excluded: stats.excluded,
+                // Checked in `calculate_data()` once it's known which commits get reported
+                signature: SignatureStatus::Unsigned,
+            },
+            stats.prediction_attributes(),
+        );
+
+        // The gaps of reported commits are only known once the walk is done if they're measured
+        // between matching commits
+        if delta_t < options.time_allowed && !is_marker && !(passes_filters && per_author) {
+            out_estimator.insert(&commit_data.1, delta_t);
+        }
+
+        if passes_filters {
+            out_vec.push(commit_data);
+            out_markers.push(is_marker);
+        }
+
+    }
+
+    // Measures the gap of every reported commit from the previous matching commit in the walk
+    if per_author {
+        for i in 0..out_vec.len() {
+            if out_markers[i] {
+                continue;
+            }
+
+            // The oldest commit (that was walked) always starts a new session
+            let delta_t = match out_vec.get(i + 1) {
+                Some(previous) => (out_vec[i].0.end - previous.0.end).max(0),
+                None => options.time_allowed,
+            };
+
+            let (value, attributes) = &mut out_vec[i];
+            value.delta_t = delta_t;
+            value.start = value.end - delta_t;
+
+            if delta_t < options.time_allowed {
+                out_estimator.insert(attributes, delta_t);
+            }
+        }
+    }
+
+    return Ok((out_vec, out_estimator, walked));
+
+}
+
+/// Splits a session into a part for every day it covers in a timezone (newest first.)
+/// ```rust
+/// # use git_stats_web::{calendar::CalendarValue, utils::split_at_midnight};
+/// # use chrono::FixedOffset;
+/// let value = CalendarValue {
+///     title: String::new(),
+///     commit: String::new(),
+///     delta_t: 7200,
+///     start: 1704065400, // 2023-12-31 23:30 UTC
+///     end: 1704072600,   // 2024-01-01 01:30 UTC
+///     projected: false,
+///     overridden: false,
+///     offset_minutes: 0,
+///     author: String::new(),
+///     co_authors: Vec::new(),
+///     repo: String::new(),
+///     branches: Vec::new(),
+///     excluded: Default::default(),
+///     signature: Default::default(),
+/// };
+///
+/// let parts = split_at_midnight(value.clone(), FixedOffset::east_opt(0).unwrap());
+/// assert_eq!(parts.iter().map(|v| (v.start, v.delta_t)).collect::<Vec<_>>(), vec![(1704067200, 5400), (1704065400, 1800)]);
+///
+/// // It's already the next day at the start of the session in UTC+1
+/// let parts = split_at_midnight(value, FixedOffset::east_opt(3600).unwrap());
+/// assert_eq!(parts.len(), 1);
+/// ```
+pub fn split_at_midnight(value: CalendarValue, timezone: FixedOffset) -> Vec<CalendarValue> {
+
+    let mut parts = Vec::new();
+    let mut remaining = value;
+
+    loop {
+        let local_end = DateTime::from_timestamp(remaining.end, 0).map(|v| v.with_timezone(&timezone));
+
+        // The start of the day the session ends in
+        let midnight = local_end
+            .and_then(|v| v.date_naive().and_time(NaiveTime::MIN).and_local_timezone(timezone).earliest())
+            .map(|v| v.timestamp());
+
+        let Some(midnight) = midnight.filter(|v| remaining.start < *v && *v < remaining.end) else {
+            parts.push(remaining);
+            return parts;
+        };
+
+        let mut part = remaining.clone();
+        part.start = midnight;
+        part.delta_t = part.end - part.start;
+        parts.push(part);
+
+        remaining.end = midnight;
+        remaining.delta_t = remaining.end - remaining.start;
+    }
+
+}
+
+/// Parses a UTC offset such as `+05:30`, `-0800`, `UTC-8` or `UTC`.
+/// A leading space is read as a `+` since that's what `+` decodes to in a query string.
+/// ```rust
+/// # use git_stats_web::utils::parse_utc_offset;
+/// assert_eq!(parse_utc_offset("+05:30").unwrap().local_minus_utc(), 5 * 3600 + 30 * 60);
+/// assert_eq!(parse_utc_offset(" 0530").unwrap().local_minus_utc(), 5 * 3600 + 30 * 60);
+/// assert_eq!(parse_utc_offset("UTC-8").unwrap().local_minus_utc(), -8 * 3600);
+/// assert_eq!(parse_utc_offset("utc").unwrap().local_minus_utc(), 0);
+/// assert!(parse_utc_offset("Europe/Paris").is_err());
+/// assert!(parse_utc_offset("+25:00").is_err());
+/// ```
+pub fn parse_utc_offset(value: &str) -> Result<FixedOffset, String> {
+
+    let error = || format!("Invalid timezone `{}` (expected a UTC offset such as `+05:30` or `-08:00`)", value);
+
+    let upper = value.trim_end().to_uppercase();
+    let offset = upper
+        .strip_prefix("UTC")
+        .or(upper.strip_prefix("GMT"))
+        .unwrap_or(&upper);
+
+    if offset.trim().is_empty() || offset == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
     }
 
-    let mut diff_opts = DiffOptions::new();
+    let (sign, digits) = match offset.chars().next() {
+        Some('-') => (-1, &offset[1..]),
+        Some('+' | ' ') => (1, &offset[1..]),
+        _ => (1, offset),
+    };
 
-    let Ok(parent) = commit.parent(0) else {
-        return;
+    let (hours, minutes) = match digits.split_once(':') {
+        Some(v) => v,
+        None if digits.len() > 2 => digits.split_at(digits.len() - 2),
+        None => (digits, "0"),
     };
 
-    let diff = repo.diff_tree_to_tree(
-        Some(&parent.tree().unwrap()),
-        Some(&commit.tree().unwrap()),
-        Some(
-            diff_opts
-                .force_text(true)
-            )
-        )
-        .unwrap()
-        .stats()
-        .unwrap();
-
-    let timestamp = commit.time().seconds();
-    let prev_timestamp = parent.time().seconds();
-    let delta_t = timestamp - prev_timestamp;
-
-    let commit_data: AnnotatedCalendarValue = (
-        CalendarValue {
-            title: commit.message().unwrap_or("MESSAGE_NOT_FOUND").trim().to_string(),
-            author: commit.author().to_string(),
-            delta_t,
-            start: prev_timestamp,
-            end: timestamp,
-            projected: false,
-        },
-        vec![
-            (PredictionAttributes::FilesChanged, diff.files_changed() as i32),
-            (PredictionAttributes::LinesAdded, diff.insertions() as i32),
-            (PredictionAttributes::LinesRemoved, diff.deletions() as i32),
-        ]
-    );
-
-    out_vec.push(commit_data);
-
-    if delta_t < args.time_allowed {
-        out_pred_struct.insert_item(PredictionAttributes::FilesChanged, diff.files_changed() as i32, delta_t);
-        out_pred_struct.insert_item(PredictionAttributes::LinesAdded, diff.insertions() as i32, delta_t);
-        out_pred_struct.insert_item(PredictionAttributes::LinesRemoved, diff.deletions() as i32, delta_t);
-    }
-
-    for parent in commit.parents() {
-        recurs_search_trees(args.clone(), repo, parent, out_vec, searched_commits, out_pred_struct);
+    let hours = hours.parse::<i32>().map_err(|_| error())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| error())?;
+
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return Err(error());
     }
 
+    return FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(error);
+
 }
 
-/// Function for getting commit data and returning json
-pub fn calculate_data(args: Arc<CliArgs>, repo: &Repository) -> Vec<CalendarValue> {
+/// Merges the timelines of several repositories into a single timeline (newest first.)
+/// Time that is covered by commits from more than one repository only gets counted once: each
+/// commit only keeps the part of its time that isn't covered by a commit that started before it
+/// (so the total is the length of the union of every commit's time.)
+/// ```rust
+/// # use git_stats_web::{calendar::CalendarValue, utils::merge_timelines};
+/// let value = |repo: &str, start, end| CalendarValue {
+///     title: String::new(),
+///     commit: String::new(),
+///     delta_t: end - start,
+///     start,
+///     end,
+///     projected: false,
+///     overridden: false,
+///     offset_minutes: 0,
+///     author: String::new(),
+///     co_authors: Vec::new(),
+///     repo: repo.to_string(),
+///     branches: Vec::new(),
+///     excluded: Default::default(),
+///     signature: Default::default(),
+/// };
+///
+/// let timeline = merge_timelines(vec![
+///     vec![value("a", 100, 200), value("a", 0, 100)],
+///     vec![value("b", 150, 300)],
+/// ]);
+///
+/// assert_eq!(timeline.iter().map(|v| (v.start, v.end)).collect::<Vec<_>>(), vec![(200, 300), (100, 200), (0, 100)]);
+/// assert_eq!(timeline.iter().map(|v| v.delta_t).sum::<i64>(), 300);
+///
+/// // A session within a longer one doesn't take time away from it
+/// let timeline = merge_timelines(vec![
+///     vec![value("a", 0, 100)],
+///     vec![value("b", 50, 60)],
+/// ]);
+///
+/// assert_eq!(timeline.iter().map(|v| (v.start, v.end)).collect::<Vec<_>>(), vec![(0, 100), (60, 60)]);
+/// assert_eq!(timeline.iter().map(|v| v.delta_t).sum::<i64>(), 100);
+/// ```
+pub fn merge_timelines(timelines: Vec<Vec<CalendarValue>>) -> Vec<CalendarValue> {
+
+    let mut values = timelines.into_iter().flatten().collect::<Vec<CalendarValue>>();
+    values.sort_by_key(|v| (v.start, v.end));
+
+    // Clips every commit to the time that isn't covered by a commit that started earlier, which
+    // is always the time after the latest end seen so far
+    let mut covered_until = Timestamp::MIN;
+    for value in values.iter_mut() {
+        value.start = value.start.max(covered_until).min(value.end);
+        value.delta_t = value.end - value.start;
+        covered_until = covered_until.max(value.end);
+    }
+
+    values.sort_by_key(|v| Reverse((v.end, v.start)));
+    return values;
 
-    let mut commit_arr: Vec<AnnotatedCalendarValue> = Vec::new();
+}
 
-    let head = git::get_head_commit(repo);
-    let mut prediction = PredictionStructure::new();
+/// Function for getting commit data and returning json
+/// The commits reachable from every selected reference are merged into a single timeline (newest
+/// first) where each commit only shows up once.
+/// Only commits missing from `stats_cache` get diffed, see `StatsCache::new_stats()` for the
+/// stats that should be stored afterwards.
+/// Only commits within the report window of `options` are returned, but every walked commit is
+/// used for making predictions.
+/// `progress` is called with the amount of commits read so far while the history is walked.
+/// The tags of every commit reachable from the refs are returned as milestones (if they're in the
+/// report window.)
+/// Commits with a start time override (see `git::get_start_overrides()`) keep that start instead
+/// of a prediction if `options.start_overrides` is set.
+/// ```rust
+/// # use git_stats_web::{cli::CliArgs, filter::IdentityFilter, git::MergePolicy, stats::StatsCache, utils::{calculate_data, AnalysisOptions}};
+/// # use git2::{Repository, Signature, Time};
+/// # use clap::Parser;
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let repo = Repository::init(dir.path()).unwrap();
+/// # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+/// let mut commit = |name: &str, time: i64| {
+///     let signature = Signature::new(name, &format!("{name}@example.com"), &Time::new(time, 0)).unwrap();
+///     let parent = repo.head().ok().and_then(|v| v.peel_to_commit().ok());
+///     repo.commit(Some("HEAD"), &signature, &signature, name, &tree, &parent.iter().collect::<Vec<_>>()).unwrap();
+/// };
+///
+/// // Bob commits in between Alice's commits
+/// commit("Alice", 1_700_000_000);
+/// commit("Bob", 1_700_000_600);
+/// commit("Alice", 1_700_001_200);
+///
+/// let mut options = AnalysisOptions::new(&CliArgs::parse_from(["git-stats-web"]));
+/// options.author_filter = IdentityFilter::new(Some("Alice"), None).unwrap();
+/// let report = calculate_data(&repo, &options, &mut StatsCache::new(), &mut |_| {}).unwrap();
+///
+/// // Alice's time is measured from her own previous commit
+/// assert_eq!(report.events.len(), 2);
+/// assert_eq!((report.events[0].start, report.events[0].delta_t), (1_700_000_000, 1200));
+///
+/// // A merge that is the oldest walked commit is only a marker, it doesn't get a predicted time
+/// let signature = Signature::new("Alice", "alice@example.com", &Time::new(1_700_001_800, 0)).unwrap();
+/// let head = repo.head().unwrap().peel_to_commit().unwrap();
+/// let side = repo.commit(None, &signature, &signature, "Side", &tree, &[&head.parent(0).unwrap()]).unwrap();
+/// repo.commit(Some("HEAD"), &signature, &signature, "Merge", &tree, &[&head, &repo.find_commit(side).unwrap()]).unwrap();
+/// commit("Alice", 1_700_002_400);
+///
+/// let mut options = AnalysisOptions::new(&CliArgs::parse_from(["git-stats-web"]));
+/// options.merge_policy = MergePolicy::Marker;
+/// options.max_commits = Some(2);
+/// let report = calculate_data(&repo, &options, &mut StatsCache::new(), &mut |_| {}).unwrap();
+///
+/// assert_eq!(report.events.len(), 2);
+/// assert_eq!((report.events[1].title.as_str(), report.events[1].delta_t, report.events[1].projected), ("Merge", 0, false));
+/// ```
+pub fn calculate_data(repo: &Repository, options: &AnalysisOptions, stats_cache: &mut StatsCache, progress: &mut dyn FnMut(usize)) -> Result<Report, AppError> {
 
-    let mut searched_commits: BTreeSet<Oid> = BTreeSet::new();
+    let tips = git::resolve_refs(repo, &options.refs)?;
+    debug!("Walking commits from refs: {:?}", tips);
+
+    let mailmap = git::get_mailmap(repo, &options.aliases).map_err(|e| AppError {
+        cause: Some(format!("Can't create mailmap with aliases: `{:?}`. Error: {}", options.aliases, e.message())),
+        message: Some("Failed to read identity aliases!".to_string()),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
 
     // Gets all the data
-    recurs_search_trees(args.clone(), repo, head, &mut commit_arr, &mut searched_commits, &mut prediction);
+    let (mut commit_arr, estimator, walked) = search_trees(repo, &tips, options, &mailmap, stats_cache, progress).map_err(|e| AppError {
+        cause: Some(format!("Can't search commits from refs: `{:?}`. Error: {}", tips, e.message())),
+        message: Some("Failed to read commit history!".to_string()),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    // Merges the commits from every tip into a single timeline
+    commit_arr.sort_by_key(|v| Reverse(v.0.end));
+
+    // Uses the manually set start times
+    if options.start_overrides {
+        let overrides = git::get_start_overrides(repo);
+
+        for item in commit_arr.iter_mut() {
+            let Some(start) = Oid::from_str(&item.0.commit).ok().and_then(|v| overrides.get(&v)) else {
+                continue;
+            };
+
+            item.0.start = (*start).min(item.0.end);
+            item.0.delta_t = item.0.end - item.0.start;
+            item.0.overridden = true;
+        }
+    }
 
     // Adds data the commit_arr
     // let max_commit_depth = 25;
     // for _i in 0..max_commit_depth {
 
+    let is_marker = |value: &CalendarValue| options.merge_policy == MergePolicy::Marker
+        && Oid::from_str(&value.commit).ok().and_then(|v| repo.find_commit(v).ok()).is_some_and(|v| v.parent_count() > 1);
+
     let output_arr = commit_arr
-        .split_inclusive(|v| (&args).time_allowed <= v.0.delta_t)
+        .split_inclusive(|v| options.time_allowed <= v.0.delta_t)
         .collect::<Vec<&[AnnotatedCalendarValue]>>()
         .iter_mut()
         .map(|v| {
             let mut items = v.to_vec();
             let item = items.last_mut().unwrap();
 
+            // Keeps the start that was set manually, and markers take no time even if they're the
+            // oldest walked commit (which closes the last session)
+            if item.0.overridden || is_marker(&item.0) {
+                return items;
+            }
+
             // Makes prediction for last item
-            let prediction = prediction.predict(&item.1);
+            let prediction = estimator.predict(&item.1);
 
             // Updates item with projections
             item.0.delta_t = prediction;
@@ -227,10 +750,40 @@ pub fn calculate_data(args: Arc<CliArgs>, repo: &Repository) -> Vec<CalendarValu
     // Converts the list of list of `CommitData`s into a single array of `CalendarValues`s
     for item_lst in output_arr {
         for value in item_lst {
-            calendar_items.push(value.0);
+            let mut parts = match options.timezone {
+                // Every part is within a single day so it's in the report if its day is
+                Some(timezone) => split_at_midnight(value.0, timezone)
+                    .into_iter()
+                    .filter(|v| options.in_window(v.start))
+                    .collect(),
+                None => match options.in_window(value.0.end) {
+                    true => vec![value.0],
+                    false => Vec::new(),
+                },
+            };
+
+            // Only reported commits get checked since verifying a signature starts a process
+            if let Some(oid) = parts.first().and_then(|v| Oid::from_str(&v.commit).ok()) {
+                let signature = options.signatures.status(repo, oid);
+                for part in parts.iter_mut() {
+                    part.signature = signature.clone();
+                }
+            }
+
+            calendar_items.extend(parts);
         }
     }
 
-    return calendar_items;
+    let mut milestones = git::get_milestones(repo, &walked).map_err(|e| AppError {
+        cause: Some(format!("Can't read tags of refs: `{:?}`. Error: {}", tips, e.message())),
+        message: Some("Failed to read tags!".to_string()),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+    milestones.retain(|v| options.in_window(v.date));
+
+    return Ok(Report {
+        events: calendar_items,
+        milestones,
+    });
 }
 
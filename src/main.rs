@@ -1,7 +1,7 @@
 use git2::Repository;
 use templates::WithBase;
-use std::{env, path::Path};
-use serde::Deserialize;
+use std::{cmp::Reverse, collections::HashSet, env, future::{ready, Ready}, path::Path, sync::Arc};
+use serde::{Deserialize, Serialize};
 use clap::Parser;
 use log::{debug, info, warn};
 
@@ -9,7 +9,7 @@ use url::Url;
 
 use sqlx::{migrate::MigrateDatabase, query, Sqlite, SqlitePool};
 
-use actix_web::{http::{header::{ContentType, WARNING}, StatusCode}, middleware, web::{self, Data, Json}, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{dev::Payload, http::{header::{ContentType, WARNING}, StatusCode}, middleware, web::{self, Data, Json}, App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
 use actix_files::Files;
 
@@ -23,7 +23,7 @@ mod auth;
 mod ws;
 
 use git_stats_web::{
-    aliases::*, calendar::CalendarValue, cli::{self, CliArgs}, database::User, errors, git, utils
+    aliases::*, cache::RepoCache, calendar::Report, cli::{self, CliArgs}, database::{self, Commits, IdentityAliases, RepoGroups, Repos, User, UserCredentials}, errors, git, jobs::{Job, JobQueue}, local::{LocalRepo, LocalRoots}, lock, secrets::SecretCipher, signature::SignatureVerifier, stats::StatsCache, utils
 };
 
 /// The URL to the SQLite database.
@@ -52,21 +52,426 @@ async fn not_found(_req: HttpRequest) -> HttpResponse {
         .body(response.into_string());
 }
 
-/// Function for getting commit data and returning json
-async fn get_data(req: HttpRequest, args: Data<CliArgs>) -> Result<Json<Vec<CalendarValue>>, errors::AppError> {
-
-    let src_url = match web::Query::<templates::calendar::RepoUrl>::from_query((&req).query_string()) {
-        Ok(v) => v,
+/// Parses the analysis parameters of an api request.
+fn parse_params(query: &str) -> Result<templates::calendar::RepoUrl, errors::AppError> {
+    return match web::Query::<templates::calendar::RepoUrl>::from_query(query) {
+        Ok(v) => Ok(v.into_inner()),
         Err(_) => {
-            return Err(errors::AppError {
-                cause: Some(format!("Invalid get request parameters! Query: `{}`", req.query_string())),
+            Err(errors::AppError {
+                cause: Some(format!("Invalid get request parameters! Query: `{}`", query)),
                 message: Some("Invalid get request parameters!".to_string()),
                 error_type: StatusCode::BAD_REQUEST,
+            })
+        },
+    };
+}
+
+/// The server state needed for analyzing repos.
+/// Cheap to clone so it can be moved into background jobs.
+#[derive(Clone)]
+struct AnalysisContext {
+    args: Data<CliArgs>,
+    local_roots: Data<LocalRoots>,
+    repo_cache: Data<RepoCache>,
+    signatures: Data<SignatureVerifier>,
+    secrets: Data<SecretCipher>,
+    db: DbPool,
+}
+
+impl AnalysisContext {
+
+    /// Gets the server state from the global values of the app.
+    fn from_app_data(req: &HttpRequest) -> Result<Self, errors::AppError> {
+        return Ok(Self {
+            args: app_data(req)?,
+            local_roots: app_data(req)?,
+            repo_cache: app_data(req)?,
+            signatures: app_data(req)?,
+            secrets: app_data(req)?,
+            db: app_data(req)?,
+        });
+    }
+
+}
+
+impl FromRequest for AnalysisContext {
+    type Error = errors::AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        return ready(Self::from_app_data(req));
+    }
+}
+
+/// Gets a global value set with `App::app_data()`.
+fn app_data<T: 'static>(req: &HttpRequest) -> Result<Data<T>, errors::AppError> {
+    return req.app_data::<Data<T>>().cloned().ok_or_else(|| errors::AppError {
+        cause: Some(format!("Missing app data: `{}`", std::any::type_name::<T>())),
+        message: Some("The server isn't configured correctly!".to_string()),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
+    });
+}
+
+/// The repos and options requested by an api request.
+struct AnalysisRequest {
+    urls: Vec<String>,
+    options: utils::AnalysisOptions,
+    user_id: Option<i64>,
+    /// Set if the repos are merged into a single timeline (see `utils::merge_timelines()`.)
+    merge: bool,
+}
+
+/// The response for a newly started background job.
+#[derive(Debug, Serialize)]
+struct JobStarted {
+    id: String,
+}
+
+/// Gets the analysis options of a request, with the identity aliases of the user.
+async fn request_options(params: &templates::calendar::RepoUrl, context: &AnalysisContext, user_id: Option<i64>) -> Result<utils::AnalysisOptions, errors::AppError> {
+
+    let mut options = params.analysis_options(&context.args)?;
+
+    // Adds the identity aliases of the user
+    if let Some(user_id) = user_id {
+        options.aliases = IdentityAliases::from_user(user_id, &**context.db).await;
+    }
+
+    // Shares the server's verifier so verified signatures stay cached
+    options.signatures = context.signatures.clone().into_inner();
+
+    return Ok(options);
+}
+
+/// Reads the request for analyzing a single repo.
+async fn repo_request(req: &HttpRequest, session: &Session, context: &AnalysisContext) -> Result<AnalysisRequest, errors::AppError> {
+
+    let params = parse_params(req.query_string())?;
+
+    let user_id = User::from_session(session, &**context.db).await.and_then(|v| v.id);
+
+    return Ok(AnalysisRequest {
+        options: request_options(&params, context, user_id).await?,
+        urls: vec![params.url],
+        user_id,
+        merge: false,
+    });
+}
+
+/// Reads the request for analyzing several repos as a single timeline.
+/// The repos are set with repeated `url` parameters and/or the name of a saved `group`.
+async fn group_request(req: &HttpRequest, session: &Session, context: &AnalysisContext) -> Result<AnalysisRequest, errors::AppError> {
+
+    let pairs = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .collect::<Vec<(String, String)>>();
+
+    // Every other parameter is parsed the same way as for a single repo
+    let params = parse_params(
+        &url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs.iter().filter(|(k, _v)| k != "url"))
+            .finish()
+        )?;
+
+    let user_id = User::from_session(session, &**context.db).await.and_then(|v| v.id);
+    let options = request_options(&params, context, user_id).await?;
+
+    let mut urls = pairs
+        .into_iter()
+        .filter(|(k, v)| k == "url" && !v.trim().is_empty())
+        .map(|(_k, v)| v)
+        .collect::<Vec<String>>();
+
+    if let Some(name) = &params.group {
+        let Some(user_id) = user_id else {
+            return Err(errors::AppError {
+                cause: Some(format!("Can't get repo group `{}` without being logged in!", name)),
+                message: Some("Log in to use saved repo groups!".to_string()),
+                error_type: StatusCode::UNAUTHORIZED,
             });
+        };
+
+        match RepoGroups::from_user_and_name(user_id, name, &**context.db).await {
+            Some(group) => urls.extend(group.urls),
+            None => {
+                return Err(errors::AppError {
+                    cause: Some(format!("Can't find repo group `{}` for user: `{}`", name, user_id)),
+                    message: Some(format!("Can't find repo group `{}`!", name)),
+                    error_type: StatusCode::NOT_FOUND,
+                });
+            },
+        }
+    }
+
+    // Analyzes every repo once (keeping the order they were requested in)
+    let mut requested = HashSet::new();
+    urls.retain(|v| requested.insert(v.clone()));
+
+    if urls.is_empty() {
+        return Err(errors::AppError {
+            cause: Some(format!("No repos requested! Query: `{}`", req.query_string())),
+            message: Some("Set at least one `url` or a `group`!".to_string()),
+            error_type: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    return Ok(AnalysisRequest {
+        urls,
+        options,
+        user_id,
+        merge: true,
+    });
+}
+
+/// Function for getting commit data and returning json
+async fn get_data(req: HttpRequest, session: Session, context: AnalysisContext) -> Result<Json<Report>, errors::AppError> {
+
+    let request = repo_request(&req, &session, &context).await?;
+
+    return Ok(Json(run_analysis(&request, &context, &Arc::new(Job::detached())).await?));
+}
+
+/// Function for getting the commit data of several repos as a single timeline.
+/// The repos are set with repeated `url` parameters and/or the name of a saved `group`.
+async fn get_group_data(req: HttpRequest, session: Session, context: AnalysisContext) -> Result<Json<Report>, errors::AppError> {
+
+    let request = group_request(&req, &session, &context).await?;
+
+    return Ok(Json(run_analysis(&request, &context, &Arc::new(Job::detached())).await?));
+}
+
+/// Starts analyzing a single repo in the background (takes the same parameters as `/api/repo`.)
+async fn start_repo_job(req: HttpRequest, session: Session, context: AnalysisContext, jobs: Data<JobQueue>) -> Result<HttpResponse, errors::AppError> {
+
+    let request = repo_request(&req, &session, &context).await?;
+
+    return Ok(start_job(request, context, jobs));
+}
+
+/// Starts analyzing several repos in the background (takes the same parameters as `/api/repos`.)
+async fn start_group_job(req: HttpRequest, session: Session, context: AnalysisContext, jobs: Data<JobQueue>) -> Result<HttpResponse, errors::AppError> {
+
+    let request = group_request(&req, &session, &context).await?;
+
+    return Ok(start_job(request, context, jobs));
+}
+
+/// Queues an analysis and responds with the id of the job.
+/// The progress can be followed at `/api/jobs/{id}/ws` and the result is at `/api/jobs/{id}`.
+fn start_job(request: AnalysisRequest, context: AnalysisContext, jobs: Data<JobQueue>) -> HttpResponse {
+
+    let (id, job) = jobs.create();
+    info!("Queued job `{id}` for repos: {:?}", request.urls);
+
+    let job_id = id.clone();
+    actix_web::rt::spawn(async move {
+        let _permit = jobs.permit().await;
+        debug!("Starting job: `{job_id}`");
+
+        let result = run_analysis(&request, &context, &job).await;
+
+        match &result {
+            Ok(v) => info!("Job `{job_id}` finished with {} events!", v.events.len()),
+            Err(e) => warn!("Job `{job_id}` failed with error: `{:?}`", e),
+        }
+
+        job.finish(result);
+    });
+
+    return HttpResponse::Accepted().json(JobStarted { id });
+}
+
+/// Gets the result of a background job.
+/// Responds with the job's status (and `202 Accepted`) while it's still running.
+async fn get_job(path: web::Path<String>, jobs: Data<JobQueue>) -> Result<HttpResponse, errors::AppError> {
+
+    let id = path.into_inner();
+
+    let Some(job) = jobs.get(&id) else {
+        return Err(errors::AppError {
+            cause: Some(format!("Can't find job: `{}`", id)),
+            message: Some("Job not found! (it may have expired)".to_string()),
+            error_type: StatusCode::NOT_FOUND,
+        });
+    };
+
+    return match job.result() {
+        Some(result) => Ok(HttpResponse::Ok().json(result?)),
+        None => Ok(HttpResponse::Accepted().json(job.status())),
+    };
+}
+
+/// The form for setting the start time of a commit.
+#[derive(Debug, Deserialize)]
+struct StartOverrideForm {
+    /// The url of the repo.
+    url: String,
+    /// The id of the commit.
+    commit: String,
+    /// The RFC 3339 date the commit was started at, the override is removed if this is empty.
+    #[serde(default)]
+    start: String,
+}
+
+/// Sets (or removes) the start time of a commit.
+/// The start time is written to the repo's notes (see `git::START_NOTES_REF`) and pushed to the
+/// remote so it's used instead of a prediction by every analysis of the repo.
+async fn set_start_override(session: Session, context: AnalysisContext, form: web::Form<StartOverrideForm>) -> Result<HttpResponse, errors::AppError> {
+
+    let form = form.into_inner();
+
+    if !context.args.allow_note_writes {
+        return Err(errors::AppError {
+            cause: Some(format!("Start time override was requested but note writes aren't allowed! Form: `{:?}`", form)),
+            message: Some("Setting start times isn't enabled! (must be enabled with `--allow-note-writes`)".to_string()),
+            error_type: StatusCode::FORBIDDEN,
+        });
+    }
+
+    let Some(user) = User::from_session(&session, &**context.db).await else {
+        return Err(errors::AppError {
+            cause: Some(format!("Can't set a start time without being logged in! Form: `{:?}`", form)),
+            message: Some("Log in to set start times!".to_string()),
+            error_type: StatusCode::UNAUTHORIZED,
+        });
+    };
+
+    let bad_request = |message: String| errors::AppError {
+        cause: Some(format!("{} (form: `{:?}`)", message, form)),
+        message: Some(message),
+        error_type: StatusCode::BAD_REQUEST,
+    };
+
+    let commit_id = git2::Oid::from_str(form.commit.trim())
+        .map_err(|_| bad_request(format!("Invalid commit id: `{}`", form.commit)))?;
+
+    let start = match form.start.trim() {
+        "" => None,
+        v => Some(chrono::DateTime::parse_from_rfc3339(v).map_err(|_| bad_request(format!("Invalid start time `{}` (expected an RFC 3339 date)", v)))?),
+    };
+
+    let url = Url::parse(&form.url).map_err(|_| bad_request(format!("Failed to parse URL from: `{}`", form.url)))?;
+
+    // Pushes only use the credentials the user stored so nobody can write with the server's key
+    let push_credentials = match (url.scheme(), url.host_str(), user.id) {
+        ("file", _, _) => None,
+        (_, Some(host), Some(user_id)) => {
+            let Some(user_credentials) = UserCredentials::from_user_and_host(user_id, &host.to_lowercase(), &context.secrets, &**context.db).await else {
+                return Err(errors::AppError {
+                    cause: Some(format!("User `{}` has no credentials for `{}` to push start times with", user.username, host)),
+                    message: Some(format!("Add your own ssh key or access token for `{}` to set start times!", host)),
+                    error_type: StatusCode::FORBIDDEN,
+                });
+            };
+
+            let mut credentials = git::Credentials::default();
+            user_credentials.apply_to(&mut credentials);
+            Some(credentials)
         },
-    }.0.url;
+        _ => return Err(bad_request(format!("Can't push start times to: `{}`", url))),
+    };
 
-    let url = match Url::parse(&src_url) {
+    let OpenedRepo { url, repo, lock: repo_lock } = open_repo(url.as_str(), user.id, &context, &Arc::new(Job::detached())).await?;
+
+    run_blocking(move || {
+        let commit = repo.find_commit(commit_id).map_err(|e| errors::AppError {
+            cause: Some(format!("Can't find commit `{}` in repo `{}`. Error: {}", commit_id, url, e.message())),
+            message: Some(format!("Can't find commit `{}`!", commit_id)),
+            error_type: StatusCode::NOT_FOUND,
+        })?;
+
+        if start.is_some_and(|v| v.timestamp() > commit.time().seconds()) {
+            return Err(errors::AppError {
+                cause: Some(format!("Start time `{:?}` is after commit `{}`", start, commit_id)),
+                message: Some("The start time can't be after the commit!".to_string()),
+                error_type: StatusCode::BAD_REQUEST,
+            });
+        }
+
+        let note_error = |e: git2::Error| errors::AppError {
+            cause: Some(format!("Can't write start time note on commit `{}` in repo `{}`. Error: {}", commit_id, url, e.message())),
+            message: Some("Failed to set the start time!".to_string()),
+            error_type: StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let previous = repo.refname_to_id(git::START_NOTES_REF).ok();
+        let signature = git2::Signature::now(&user.username, &user.email).map_err(note_error)?;
+        git::set_start_override(&repo, commit_id, start, &signature).map_err(note_error)?;
+
+        // Cached repos get the remote's notes on every fetch so they have to be pushed
+        if let Some(credentials) = push_credentials {
+            git::push_start_overrides(&repo, &credentials, previous)?;
+        }
+
+        info!("User `{}` set the start time of commit `{}` in repo `{}` to: {:?}", user.username, commit_id, url, start);
+        return Ok(());
+    }).await?;
+
+    drop(repo_lock);
+
+    return Ok(HttpResponse::NoContent().finish());
+}
+
+/// Function for listing the repos found under the local roots.
+async fn get_local_repos(args: Data<CliArgs>, local_roots: Data<LocalRoots>, query: web::Query<templates::local::LocalReposQuery>) -> Result<Json<Vec<LocalRepo>>, errors::AppError> {
+
+    if !args.allow_local || local_roots.is_empty() {
+        return Err(errors::AppError {
+            cause: Some("Local repos were requested but no local roots are allowed!".to_string()),
+            message: Some("Local repos aren't enabled! (must be enabled with `--allow-local` and `--local-root`)".to_string()),
+            error_type: StatusCode::FORBIDDEN,
+        });
+    }
+
+    return Ok(Json(local_roots.repos(query.refresh.unwrap_or(false))));
+}
+
+/// Analyzes every repo of a request, reporting the progress to `job`.
+async fn run_analysis(request: &AnalysisRequest, context: &AnalysisContext, job: &Arc<Job>) -> Result<Report, errors::AppError> {
+
+    let mut timelines = Vec::new();
+    let mut milestones = Vec::new();
+    for src_url in &request.urls {
+        let report = analyze_repo(src_url, &request.options, request.user_id, context, job).await?;
+        timelines.push(report.events);
+        milestones.extend(report.milestones);
+    }
+
+    milestones.sort_by_key(|v| Reverse(v.date));
+
+    let events = match request.merge {
+        true => utils::merge_timelines(timelines),
+        false => timelines.into_iter().flatten().collect(),
+    };
+
+    return Ok(Report { events, milestones });
+}
+
+/// Runs blocking git work on a separate thread so the server can keep responding (e.g. with the
+/// progress of jobs) while a repo gets cloned or analyzed.
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, errors::AppError> + Send + 'static) -> Result<T, errors::AppError> {
+    return tokio::task::spawn_blocking(f).await.map_err(|e| errors::AppError {
+        cause: Some(format!("Blocking task failed with error: `{:?}`", e)),
+        message: Some("Failed to analyze the repo!".to_string()),
+        error_type: StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+}
+
+/// A repo that was fetched into the cache (or opened from a local root) for a request.
+struct OpenedRepo {
+    url: Url,
+    repo: Repository,
+    /// Keeps other requests from fetching into (or evicting) a cached repo while it's used.
+    lock: Option<lock::RepoLock>,
+}
+
+/// Fetches a remote repo into the cache or opens a local repo, reporting the fetch progress to
+/// `job`.
+async fn open_repo(src_url: &str, user_id: Option<i64>, context: &AnalysisContext, job: &Arc<Job>) -> Result<OpenedRepo, errors::AppError> {
+
+    let AnalysisContext { args, local_roots, repo_cache, secrets, db, .. } = context;
+
+    let mut url = match Url::parse(src_url) {
         Ok(v) => v,
         Err(_) => {
             return Err(errors::AppError {
@@ -77,24 +482,59 @@ async fn get_data(req: HttpRequest, args: Data<CliArgs>) -> Result<Json<Vec<Cale
         },
     };
 
-    let arc_args = args.into_inner();
-
     // Fetches repo
-    let repo = match url.scheme() {
+    return match url.scheme() {
         "http" | "https" | "ssh" => {
-            let file_path = format!("{}{}", url.authority(), url.path()).to_lowercase();
-            let repo = git::fetch_repo(
-                &src_url,
-                Path::new(&arc_args.get_tmp_path())
-                    .join(&file_path).as_path(),
-                arc_args.clone(),
-                ).unwrap();
-
-            info!("Repo Cloned to `{file_path}`!");
-            repo
+
+            // The server's credentials can read every repo its key can, so anonymous requests
+            // only get them if the server allows it
+            let mut credentials = match user_id.is_some() || args.share_server_credentials {
+                true => git::Credentials::from_args(args),
+                false => git::Credentials::default(),
+            };
+
+            // Uses the credentials the user stored for this host over the server's
+            if let (Some(user_id), Some(host)) = (user_id, url.host_str()) {
+                if let Some(user_credentials) = UserCredentials::from_user_and_host(user_id, &host.to_lowercase(), secrets, &***db).await {
+                    user_credentials.apply_to(&mut credentials);
+                }
+            }
+
+            // Credentials in the url are only used for this fetch, they're never written to disk,
+            // the database or reports (and don't create a separate clone)
+            if !url.username().is_empty() {
+                credentials.username = Some(url.username().to_string());
+            }
+            if let Some(password) = url.password() {
+                credentials.token = Some(password.to_string());
+            }
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+
+            let repo_path = repo_cache.repo_path(&url);
+
+            // Keeps other requests from fetching into (or evicting) the repo until it's analyzed
+            let repo_lock = repo_cache.lock(&repo_path).await?;
+
+            let fetch_url = url.to_string();
+            let fetch_path = repo_path.clone();
+            let fetch_job = job.clone();
+            let max_size = repo_cache.max_repo_size;
+            let repo = run_blocking(move || {
+                git::fetch_repo(&fetch_url, &fetch_path, &credentials, Some(max_size), &mut |v| fetch_job.fetch_progress(&fetch_url, v))
+            }).await?;
+            info!("Repo Cloned to `{}`!", repo_path.display());
+
+            repo_cache.track(&repo_path, &url, &***db).await?;
+
+            Ok(OpenedRepo {
+                url,
+                repo,
+                lock: Some(repo_lock),
+            })
         },
         "file" => {
-            if !(&arc_args).allow_local {
+            if !args.allow_local {
                 return Err(errors::AppError {
                     cause: Some(format!("`file://` schema is allowed but not configured! (must be enabled by CLI argument)")),
                     message: Some(format!("`file://` schema is allowed but not configured! (must be enabled by CLI argument)")),
@@ -102,31 +542,70 @@ async fn get_data(req: HttpRequest, args: Data<CliArgs>) -> Result<Json<Vec<Cale
                 });
             }
 
-            let directory = ".".to_string() + url.path();
-
-            match Repository::init(&directory) {
-                Ok(v) => {
-                    info!("Found repo in directory: `{directory}`!");
-                    v
-                }, Err(e) => {
-                    return Err(errors::AppError {
-                        cause: Some(format!("`file://` schema is configured but file not found! Error: {}", e)),
-                        message: Some(format!("`file://` schema is configured but file not found! Error: {}", e)),
-                        error_type: StatusCode::NOT_FOUND,
-                    })
-                },
-            }
+            let repo = local_roots.open_repo(&url)?;
+            info!("Found local repo for url: `{url}`!");
+
+            Ok(OpenedRepo {
+                url,
+                repo,
+                lock: None,
+            })
         },
         scheme => {
-            return Err(errors::AppError {
+            Err(errors::AppError {
                 cause: Some(format!("Can't use scheme on URL: {} (source URL: {})", scheme, url)),
                 message: Some(format!("Can't use scheme on URL: {}", scheme)),
                 error_type: StatusCode::BAD_REQUEST,
-            });
+            })
         }
     };
+}
+
+/// Fetches (or opens) a repo and gets its commit data.
+/// Diff stats are read from and added to the database's cache.
+async fn analyze_repo(src_url: &str, options: &utils::AnalysisOptions, user_id: Option<i64>, context: &AnalysisContext, job: &Arc<Job>) -> Result<Report, errors::AppError> {
+
+    let OpenedRepo { url, repo, lock: repo_lock } = open_repo(src_url, user_id, context, job).await?;
+    let db = &context.db;
 
-    return Ok(Json(utils::calculate_data(arc_args, &repo)));
+    // Loads the stats of previously analyzed commits
+    let repo_fk = Repos::id_from_url(url.as_str(), url.path().trim_matches('/'), &***db).await;
+    let stats_key = options.stats_key(&repo);
+    let mut stats_cache = match repo_fk {
+        Some(id) => Commits::stats_cache_from_repo(id, &stats_key, &***db).await,
+        None => StatsCache::new(),
+    };
+
+    let analysis_url = url.to_string();
+    let analysis_options = options.clone();
+    let analysis_job = job.clone();
+    let (mut report, stats_cache) = run_blocking(move || {
+        let max_commits = analysis_options.max_commits;
+        let report = utils::calculate_data(&repo, &analysis_options, &mut stats_cache, &mut |v| {
+            analysis_job.analysis_progress(&analysis_url, v, max_commits)
+        })?;
+        Ok((report, stats_cache))
+    }).await?;
+
+    // Caches the stats of newly analyzed commits
+    if let Some(id) = repo_fk {
+        match Commits::insert_stats(id, &stats_key, stats_cache.new_stats(), &***db).await {
+            Ok(v) => debug!("Cached the stats of {v} new commits!"),
+            Err(e) => warn!("Failed to cache commit stats with error: `{:?}`", e),
+        }
+    }
+
+    for value in report.events.iter_mut() {
+        value.repo = url.to_string();
+    }
+
+    for milestone in report.milestones.iter_mut() {
+        milestone.repo = url.to_string();
+    }
+
+    drop(repo_lock);
+
+    return Ok(report);
 }
 
 /*
@@ -216,6 +695,19 @@ async fn main() -> std::io::Result<()> {
         info!("Database Already Exists!");
     }
 
+    let local_roots = Data::new(LocalRoots::from_args(&args));
+    if !local_roots.is_empty() {
+        info!("Found {} local repos!", local_roots.repos(false).len());
+    }
+
+    let repo_cache = Data::new(RepoCache::from_args(&args));
+    let jobs = Data::new(JobQueue::from_args(&args));
+    let signatures = Data::new(SignatureVerifier::from_args(&args));
+    let secrets = Data::new(SecretCipher::from_args(&args));
+    if !secrets.is_enabled() {
+        warn!("Users can't store credentials since no secrets key is set! (see `--secrets-key`)");
+    }
+
     let db = Data::new(SqlitePool::connect(DB_URL).await.unwrap());
     let db_schema_filename = "schema.sql";
     let db_schema = match std::fs::read_to_string(db_schema_filename) {
@@ -226,8 +718,15 @@ async fn main() -> std::io::Result<()> {
         },
     };
 
+    database::migrate(&db).await.unwrap();
     let _result = query(&db_schema).execute(&**db).await.unwrap();
 
+    match UserCredentials::encrypt_stored(&secrets, &db).await {
+        Ok(0) => (),
+        Ok(v) => info!("Encrypted {v} stored credentials!"),
+        Err(e) => warn!("Failed to encrypt stored credentials with error: `{:?}`", e),
+    }
+
     debug!("Initialized repo!");
 
     // let head = get_head_commit(&repo);
@@ -253,6 +752,11 @@ async fn main() -> std::io::Result<()> {
             // Sets global values
             .app_data(Data::clone(&args))
             .app_data(Data::clone(&db))
+            .app_data(Data::clone(&local_roots))
+            .app_data(Data::clone(&repo_cache))
+            .app_data(Data::clone(&jobs))
+            .app_data(Data::clone(&signatures))
+            .app_data(Data::clone(&secrets))
             .app_data(Data::clone(&github_client))
 
             // Sets middle wares
@@ -276,6 +780,10 @@ async fn main() -> std::io::Result<()> {
 
             .route("/logout", web::get().to(auth::logout))
 
+            .route("/credentials", web::post().to(auth::credentials_handler))
+            .route("/aliases", web::post().to(auth::alias_handler))
+            .route("/repo-groups", web::post().to(auth::repo_group_handler))
+
             // Github Auth
             .route("/github/callback", web::get().to(github_callback))
 
@@ -288,17 +796,26 @@ async fn main() -> std::io::Result<()> {
             // Sets the calendar url
             .route("/repo", web::get().to(templates::calendar::calendar))
 
+            // Sets the calendar url for several repos
+            .route("/group", web::get().to(templates::calendar::group_calendar))
+
             // Sets the repo list url
             .route("/repos", web::get().to(templates::repo_list::repo_list))
 
-            // Sets an echo route.
-            // WS is a feature for the future
-            // .route("/echo", web::get().to(ws::echo))
+            // Sets the local repo list url
+            .route("/local", web::get().to(templates::local::local_repos))
 
             // Sets api endpoints
             .service(
                 web::scope("/api")
                     .route("/repo", web::get().to(get_data))
+                    .route("/repos", web::get().to(get_group_data))
+                    .route("/local-repos", web::get().to(get_local_repos))
+                    .route("/jobs/repo", web::post().to(start_repo_job))
+                    .route("/jobs/repos", web::post().to(start_group_job))
+                    .route("/jobs/{id}", web::get().to(get_job))
+                    .route("/jobs/{id}/ws", web::get().to(ws::job_progress))
+                    .route("/overrides", web::post().to(set_start_override))
                     // .service(web::resource("/repo/{site}/{username}/{repo}").to(get_data))
                 )
 
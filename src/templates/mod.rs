@@ -10,6 +10,7 @@ pub use pages::home;
 pub use pages::calendar;
 pub use pages::auth;
 pub use pages::repo_list;
+pub use pages::local;
 
 // Imports for components
 mod components;
@@ -1,11 +1,18 @@
 use std::error::Error;
 
-use actix_web::{web, HttpRequest};
-use git_stats_web::aliases::Timestamp;
+use actix_web::{http::StatusCode, web, HttpRequest};
+use git_stats_web::{
+    aliases::Timestamp,
+    cli::CliArgs,
+    filter::{IdentityFilter, PathFilter, PredictionScope, StatsExclusion},
+    git::{MergePolicy, RefSelection},
+    prediction::EstimatorKind,
+    utils::{self, AnalysisOptions},
+};
 use maud::{html, Markup, PreEscaped};
 use url::Url;
 use serde::Deserialize;
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveTime};
 
 use super::super::{
     WithBase,
@@ -15,10 +22,147 @@ use super::super::{
 
 #[derive(Debug, Deserialize)]
 pub struct RepoUrl {
+    /// The url of the repo (the multi repo endpoint reads its urls separately.)
+    #[serde(default)]
     pub url: String,
     pub date_end: Option<NaiveDate>,
     pub date_start: Option<NaiveDate>,
     pub time_allowed: Option<Timestamp>,
+    /// The refs to analyze, e.g. `all`, `local`, `remote`, `refs/heads/feature/*` or `main,dev`.
+    pub refs: Option<String>,
+    /// The maximum amount of commits to analyze (capped by the server.)
+    pub max_commits: Option<usize>,
+    /// The date to stop analyzing commits at (commits before this date are ignored.)
+    pub stop_at: Option<NaiveDate>,
+    /// Comma separated names and emails of the authors to report on.
+    pub author: Option<String>,
+    /// A regular expression matched against authors (formatted as `Name <email>`.)
+    pub author_regex: Option<String>,
+    /// Comma separated names and emails of the committers to report on.
+    pub committer: Option<String>,
+    /// A regular expression matched against committers (formatted as `Name <email>`.)
+    pub committer_regex: Option<String>,
+    /// Which commits predictions are made from, either `team` or `author`.
+    pub prediction_scope: Option<String>,
+    /// Comma separated pathspecs of the files to count.
+    pub paths: Option<String>,
+    /// Comma separated pathspecs of the files to not count.
+    pub exclude_paths: Option<String>,
+    /// Comma separated pathspecs of the files to leave out of the metrics (replaces the server's
+    /// default list.)
+    pub stats_exclude: Option<String>,
+    /// Set to `false` to ignore the `linguist-generated` and `linguist-vendored` attributes.
+    pub linguist: Option<bool>,
+    /// How merge commits are counted, either `first_parent`, `skip`, `marker` or `merge_base`.
+    pub merges: Option<String>,
+    /// The name of a saved repo group to analyze (only used by the multi repo endpoint.)
+    pub group: Option<String>,
+    /// The timezone of the viewer as a UTC offset (e.g. `+05:30`.)
+    /// Report days start at midnight in this timezone and sessions are split at midnight.
+    pub tz: Option<String>,
+    /// Set to `false` to predict every start time, ignoring the start times set in the repo's
+    /// notes.
+    pub overrides: Option<bool>,
+    /// The model that predicts the start times of sessions, either `ratio`, `regression` or
+    /// `median`.
+    pub estimator: Option<String>,
+    /// The quantile of the seconds per line used by the `median` estimator (e.g. `0.75`.)
+    pub quantile: Option<f64>,
+}
+
+impl RepoUrl {
+    /// Gets the analysis options requested by the get request parameters.
+    /// Uses the CLI arguments for anything that isn't set.
+    pub fn analysis_options(&self, args: &CliArgs) -> Result<AnalysisOptions, AppError> {
+
+        let bad_request = |message: String| AppError {
+            cause: Some(format!("{} (parameters: `{:?}`)", message, self)),
+            message: Some(message),
+            error_type: StatusCode::BAD_REQUEST,
+        };
+
+        let time_allowed = self.time_allowed.unwrap_or(args.time_allowed);
+        if time_allowed <= 0 {
+            return Err(bad_request(format!("`time_allowed` must be positive (found: `{}`)", time_allowed)));
+        }
+
+        if let (Some(start), Some(end)) = (self.date_start, self.date_end) {
+            if end < start {
+                return Err(bad_request(format!("`date_end` ({}) can't be before `date_start` ({})", end, start)));
+            }
+        }
+
+        let author_filter = IdentityFilter::new(self.author.as_deref(), self.author_regex.as_deref())
+            .map_err(|e| bad_request(format!("Invalid `author_regex`: {}", e)))?;
+
+        let committer_filter = IdentityFilter::new(self.committer.as_deref(), self.committer_regex.as_deref())
+            .map_err(|e| bad_request(format!("Invalid `committer_regex`: {}", e)))?;
+
+        let prediction_scope = match &self.prediction_scope {
+            Some(v) => v.parse::<PredictionScope>().map_err(bad_request)?,
+            None => PredictionScope::default(),
+        };
+
+        let merge_policy = match &self.merges {
+            Some(v) => v.parse::<MergePolicy>().map_err(bad_request)?,
+            None => MergePolicy::default(),
+        };
+
+        let estimator = match (&self.estimator, self.quantile) {
+            (Some(v), _) => v.parse::<EstimatorKind>().map_err(bad_request)?,
+            // Setting a quantile selects the quantile estimator
+            (None, Some(_)) => EstimatorKind::Quantile(0.5),
+            (None, None) => EstimatorKind::default(),
+        };
+
+        let estimator = match (estimator, self.quantile) {
+            (EstimatorKind::Quantile(_), Some(quantile)) => {
+                if !(0.0..=1.0).contains(&quantile) {
+                    return Err(bad_request(format!("`quantile` must be between 0 and 1 (found: `{}`)", quantile)));
+                }
+                EstimatorKind::Quantile(quantile)
+            },
+            (_, Some(_)) => return Err(bad_request("`quantile` can only be set for the `median` estimator".to_string())),
+            (v, None) => v,
+        };
+
+        let timezone = match &self.tz {
+            Some(v) => Some(utils::parse_utc_offset(v).map_err(bad_request)?),
+            None => None,
+        };
+
+        // Days start at midnight in the viewer's timezone (or UTC)
+        let to_timestamp = |date: NaiveDate| {
+            let midnight = date.and_time(NaiveTime::MIN);
+            match timezone.and_then(|v| midnight.and_local_timezone(v).earliest()) {
+                Some(v) => v.timestamp(),
+                None => midnight.and_utc().timestamp(),
+            }
+        };
+
+        return Ok(AnalysisOptions {
+            refs: self.refs.as_deref().map(RefSelection::from).unwrap_or_default(),
+            max_commits: Some(self.max_commits.unwrap_or(args.max_commits).min(args.max_commits)),
+            stop_at: self.stop_at.map(to_timestamp),
+            time_allowed,
+            date_start: self.date_start.map(to_timestamp),
+            // The end date is inclusive so the window ends at the start of the next day
+            date_end: self.date_end.and_then(|v| v.succ_opt()).map(to_timestamp),
+            timezone,
+            author_filter,
+            committer_filter,
+            prediction_scope,
+            path_filter: PathFilter::new(self.paths.as_deref(), self.exclude_paths.as_deref()),
+            stats_exclusion: StatsExclusion::new(
+                self.stats_exclude.as_deref().unwrap_or(&args.stats_exclude),
+                self.linguist.unwrap_or(true),
+            ),
+            merge_policy,
+            start_overrides: self.overrides.unwrap_or(true),
+            estimator,
+            ..AnalysisOptions::new(args)
+        });
+    }
 }
 
 // pub async fn calendar(path: web::Path<(String, String, String)>) -> Markup {
@@ -72,7 +216,53 @@ pub async fn calendar(req: HttpRequest) -> Result<Markup, AppError> {
 
     let path = (&full_url).path().trim_matches('/');
 
-    return Ok(html! {
+    // Passes every parameter through to the api (with the parsed url)
+    let api_query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("url", full_url.as_str())
+        .extend_pairs(
+            url::form_urlencoded::parse(req.query_string().as_bytes())
+                .filter(|(k, _v)| k != "url")
+        )
+        .finish();
+
+    return Ok(calendar_page(html! {
+        a #calendar-source target="_blank" href=(full_url) {
+            (path)
+        }
+    }, &format!("/api/jobs/repo?{api_query}")));
+}
+
+/// The calendar page for several repos (see the `/api/jobs/repos` endpoint.)
+/// Every parameter is passed through to the api.
+pub async fn group_calendar(req: HttpRequest) -> Result<Markup, AppError> {
+
+    let pairs = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .collect::<Vec<(String, String)>>();
+
+    let repo_count = pairs.iter().filter(|(k, _v)| k == "url").count();
+    let group = pairs.iter().find(|(k, _v)| k == "group").map(|(_k, v)| v.clone());
+
+    let title = match (group, repo_count) {
+        (Some(group), 0) => group,
+        (Some(group), count) => format!("{group} (+{count} repos)"),
+        (None, 1) => "1 repo".to_string(),
+        (None, count) => format!("{count} repos"),
+    };
+
+    // Re-encodes the parameters so nothing unescaped ends up in the script
+    let api_query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(&pairs)
+        .finish();
+
+    return Ok(calendar_page(html! { (title) }, &format!("/api/jobs/repos?{api_query}")));
+}
+
+/// The layout of a calendar page.
+/// The events are loaded by a background job started at `job_url`, which reports its progress
+/// until the events are ready.
+fn calendar_page(title: Markup, job_url: &str) -> Markup {
+    return html! {
 
         /*
         (header())
@@ -88,9 +278,7 @@ pub async fn calendar(req: HttpRequest) -> Result<Markup, AppError> {
             (icon())
             div style="flex: 3;" {
                 h1 {
-                    a #calendar-source target="_blank" href=(full_url) {
-                        (path)
-                    }
+                    (title)
                 }
             }
         }
@@ -101,8 +289,8 @@ pub async fn calendar(req: HttpRequest) -> Result<Markup, AppError> {
         }
         script {
             (PreEscaped(format!(r#"
-                updateCalendar("/api/repo/?url={full_url}");
+                updateCalendar("{job_url}");
                 "#)))
         }
-    }.template_base());
+    }.template_base();
 }
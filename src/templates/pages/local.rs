@@ -0,0 +1,58 @@
+use actix_web::web::{Data, Query};
+use git_stats_web::local::LocalRoots;
+use maud::{html, Markup};
+use serde::Deserialize;
+
+use super::super::{
+    WithBase,
+    header,
+    header_spacer,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct LocalReposQuery {
+    /// Scans the local roots again instead of using the cached scan.
+    pub refresh: Option<bool>,
+}
+
+/// Lists the repos found under the local roots so any (or all) of them can be analyzed.
+pub async fn local_repos(local_roots: Data<LocalRoots>, query: Query<LocalReposQuery>) -> Markup {
+
+    if local_roots.is_empty() {
+        return html! {
+            (header())
+            (header_spacer())
+            p { "No local roots are configured! (set them with `--allow-local --local-root <path>`)" }
+        }.template_base();
+    }
+
+    let repos = local_roots.repos(query.refresh.unwrap_or(false));
+
+    return html! {
+        (header())
+        (header_spacer())
+
+        h1 { "Local Repositories" }
+        p {
+            (repos.len()) " repos found, "
+            a href="/local?refresh=true" { "scan again" }
+        }
+
+        form method="GET" action="/group" {
+            @for repo in &repos {
+                label style="display: block;" {
+                    input type="checkbox" name="url" value=(repo.url);
+                    " " (repo.name)
+                }
+            }
+            button type="submit" { "Analyze Selected" }
+        }
+
+        form method="GET" action="/group" {
+            @for repo in &repos {
+                input type="hidden" name="url" value=(repo.url);
+            }
+            button type="submit" { "Analyze All" }
+        }
+    }.template_base();
+}
@@ -2,3 +2,4 @@ pub mod calendar;
 pub mod home;
 pub mod auth;
 pub mod repo_list;
+pub mod local;
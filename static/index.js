@@ -1,50 +1,24 @@
-async function updateCalendar(url) {
+// Starts a background job for the events at `jobUrl` and shows its progress until the calendar can be drawn.
+async function updateCalendar(jobUrl) {
 
     let bottom_text = document.getElementsByClassName("bottom-message")[0];
 
+    // Splits the report's days at the viewer's midnight unless a timezone was picked
+    const url = new URL(jobUrl, location.origin);
+    if (!url.searchParams.has("tz")) {
+        url.searchParams.set("tz", getUtcOffset());
+    }
+
     try {
-        const res = await fetch(url)
-            .then(res => res.json())
-            .then(events => {
-                // console.log(events);
-
-                let dates = ["start", "end"];
-
-                // Adds date to each event
-                for (ev in events) {
-                    for (date in dates) {
-                        events[ev][dates[date]] = new Date(events[ev][dates[date]] * 1000);
-                        events[ev]["calendarId"] = 1;
-                        events[ev]["category"] = "commit";
-                    }
-                }
-
-                var init_date = null;
-                if (events.length != 0) {
-                    init_date = events[events.length - 1]["end"]; // initializes to the newest date in the data
-                }
-
-                var calendar = new tui.Calendar("#calendar", {
-
-                    defaultView: "month",
-                    template: {
-                        time(event) {
-                            const {start, end, title} = event;
-
-                            return `<span style="color: white;">${formatTime(start)}~${formatTime(end)} ${title}</span>`;
-                        },
-                        allday(event) {
-                            return `<span style="color: grey;">${event.title}</span>`;
-                        },
-                    },
-                    calendars: events,
-                });
-                // calendar.render();
-                calendar.createEvents(events);
-
-                bottom_text.innerText = "This report was automatically generated";
-                bottom_text.className += " print-only";
-            });
+        const job = await fetch(url, { method: "POST" }).then(readResponse);
+
+        await watchJob(job.id, bottom_text);
+        const report = await getJobResult(job.id, bottom_text);
+
+        drawCalendar(report);
+
+        bottom_text.innerText = "This report was automatically generated";
+        bottom_text.className += " print-only";
     } catch (e) {
         bottom_text.innerText = `Failed to get Git Repo! (Maybe try refreshing?)`;
 
@@ -57,3 +31,130 @@ async function updateCalendar(url) {
         console.log(e);
     }
 }
+
+// Gets the UTC offset of the browser's timezone (e.g. `+05:30`.)
+function getUtcOffset() {
+    const offset = -new Date().getTimezoneOffset();
+    const pad = value => String(Math.floor(value)).padStart(2, "0");
+
+    return `${offset < 0 ? "-" : "+"}${pad(Math.abs(offset) / 60)}:${pad(Math.abs(offset) % 60)}`;
+}
+
+// Reads a json response, throwing the server's error message if the request failed.
+async function readResponse(res) {
+    const body = await res.json();
+
+    if (!res.ok) {
+        throw new Error(body.error);
+    }
+
+    return body;
+}
+
+// Escapes text (e.g. commit titles or tag names) so it can be put in the calendar's HTML templates.
+function escapeHtml(text) {
+    const replacements = { "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;", "'": "&#39;" };
+
+    return String(text).replace(/[&<>"']/g, c => replacements[c]);
+}
+
+// Shows the progress of a job (streamed over a WebSocket) until the socket gets closed.
+function watchJob(id, progress_text) {
+    return new Promise(resolve => {
+        const protocol = location.protocol === "https:" ? "wss:" : "ws:";
+        const socket = new WebSocket(`${protocol}//${location.host}/api/jobs/${id}/ws`);
+
+        socket.onmessage = message => {
+            progress_text.innerText = formatProgress(JSON.parse(message.data));
+        };
+
+        // The result is polled for if the socket can't be used
+        socket.onclose = () => resolve();
+    });
+}
+
+// Gets the report of a finished job, polling while it's still running.
+async function getJobResult(id, progress_text) {
+    while (true) {
+        const res = await fetch(`/api/jobs/${id}`);
+
+        if (res.status !== 202) {
+            return readResponse(res);
+        }
+
+        progress_text.innerText = formatProgress(await res.json());
+        await new Promise(resolve => setTimeout(resolve, 1000));
+    }
+}
+
+// Formats the status of a job for the loading message.
+function formatProgress(status) {
+    switch (status.state) {
+        case "queued":
+            return "Waiting for other reports to finish...";
+        case "fetching":
+            const megabytes = (status.received_bytes / (1024 * 1024)).toFixed(1);
+            return `Fetching ${status.repo}: ${status.received_objects}/${status.total_objects} objects (${megabytes} MB)`;
+        case "analyzing":
+            const total = status.max_commits === null ? "" : ` (at most ${status.max_commits})`;
+            return `Analyzing ${status.repo}: ${status.commits} commits${total}`;
+        case "failed":
+            return `Failed: ${status.message}`;
+        default:
+            return "Loading...";
+    }
+}
+
+// Draws the events and milestones of a report on the calendar.
+function drawCalendar(report) {
+    const events = report.events;
+    // console.log(events);
+
+    let dates = ["start", "end"];
+
+    // Adds date to each event
+    for (ev in events) {
+        for (date in dates) {
+            events[ev][dates[date]] = new Date(events[ev][dates[date]] * 1000);
+            events[ev]["calendarId"] = 1;
+            events[ev]["category"] = "commit";
+        }
+    }
+
+    var init_date = null;
+    if (events.length != 0) {
+        init_date = events[events.length - 1]["end"]; // initializes to the newest date in the data
+    }
+
+    var calendar = new tui.Calendar("#calendar", {
+
+        defaultView: "month",
+        template: {
+            time(event) {
+                const {start, end, title} = event;
+
+                return `<span style="color: white;">${formatTime(start)}~${formatTime(end)} ${escapeHtml(title)}</span>`;
+            },
+            allday(event) {
+                return `<span style="color: grey;">${escapeHtml(event.title)}</span>`;
+            },
+            milestone(event) {
+                return `<b style="color: var(--link-color);">${escapeHtml(event.title)}</b>`;
+            },
+        },
+        calendars: events,
+    });
+    // calendar.render();
+    calendar.createEvents(events);
+
+    // Adds a marker for every tag
+    calendar.createEvents(report.milestones.map(milestone => ({
+        title: milestone.tag,
+        body: milestone.message ?? "",
+        start: new Date(milestone.date * 1000),
+        end: new Date(milestone.date * 1000),
+        calendarId: 1,
+        category: "milestone",
+        isReadOnly: true,
+    })));
+}